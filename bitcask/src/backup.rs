@@ -0,0 +1,191 @@
+//! Incremental backups
+//!
+//! Immutable data files and their hint files never change, so a backup only has to copy the files
+//! that were created since the previous one, along with a sealed copy of the active file. The file
+//! that was the active file during the previous backup is copied again as well, since it may have
+//! changed without changing its size, for instance when a crash dropped unsynced writes. Every
+//! backup directory holds a manifest listing all files that make up the data store at the time of
+//! the backup, whichever backup they were copied into. Restoring a backup collects those files
+//! from the chain of backups that led up to it.
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io,
+    path::Path,
+};
+
+use tracing::{info, instrument};
+
+use crate::{fs::FsError, Cask, CaskError, System};
+
+/// Name of the manifest within a backup directory
+const MANIFEST: &str = "MANIFEST";
+
+/// The files making up a backup, identified by their name and size
+///
+/// Pass the manifest of the previous backup to [`Cask::backup_incremental`] to only copy what
+/// changed since. An empty manifest results in a full backup.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackupManifest {
+    files: BTreeMap<String, u64>,
+}
+
+impl BackupManifest {
+    /// Manifest of an empty backup target, backing up against it copies every file
+    pub fn new() -> Self {
+        BackupManifest::default()
+    }
+
+    /// Reads the manifest of the backup in `backup`
+    pub fn load(backup: impl AsRef<Path>) -> Result<Self, CaskError> {
+        let manifest = fs::read_to_string(backup.as_ref().join(MANIFEST)).map_err(FsError::from)?;
+
+        let mut files = BTreeMap::new();
+        for line in manifest.lines() {
+            let (size, name) = line
+                .split_once(' ')
+                .and_then(|(size, name)| Some((size.parse().ok()?, name)))
+                .ok_or_else(|| {
+                    FsError::from(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Malformed manifest entry: {line}"),
+                    ))
+                })?;
+            files.insert(name.to_string(), size);
+        }
+
+        Ok(BackupManifest { files })
+    }
+
+    /// Name and size of every file in the backup
+    pub fn files(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.files.iter().map(|(name, size)| (name.as_str(), *size))
+    }
+
+    /// Atomically writes the manifest into the backup directory `dir`
+    fn store(&self, dir: &Path) -> io::Result<()> {
+        let manifest: String = self
+            .files
+            .iter()
+            .map(|(name, size)| format!("{size} {name}\n"))
+            .collect();
+
+        let path = dir.join(MANIFEST);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, manifest)?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        File::open(dir)?.sync_all()
+    }
+}
+
+impl<T> Cask<T>
+where
+    T: System,
+{
+    /// Backs the data store up into the empty directory `dest`, copying only the files which are
+    /// not part of `manifest` yet
+    ///
+    /// `manifest` is the manifest of the previous backup in the chain, which is returned by this
+    /// method and can be read back with [`BackupManifest::load`]. The backup is consistent in the
+    /// same way as a [`Cask::checkpoint`], and can be restored with [`restore_backup`].
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{restore_backup, BackupManifest, Cask, ConcreteSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    /// # let dir = std::env::temp_dir().join(format!("bitcask-backup-{}", std::process::id()));
+    /// # let path = dir.join("db");
+    ///     let cask: Cask<ConcreteSystem> = Cask::new(path.to_str().unwrap())?;
+    ///     cask.insert("hello", "world")?;
+    ///     let manifest = cask.backup_incremental(dir.join("full"), &BackupManifest::new())?;
+    ///     cask.insert("hello", "there")?;
+    ///     cask.backup_incremental(dir.join("incremental"), &manifest)?;
+    ///
+    ///     restore_backup(&[dir.join("full"), dir.join("incremental")], dir.join("restored"))?;
+    ///     let restored: Cask<ConcreteSystem> = Cask::new(dir.join("restored").to_str().unwrap())?;
+    ///     assert_eq!(restored.get(&"hello")?, "there".as_bytes());
+    /// # std::fs::remove_dir_all(dir)?;
+    ///     # Ok(())
+    /// # }
+    /// ```
+    #[instrument(skip(self, dest, manifest), fields(dest = ?dest.as_ref()))]
+    pub fn backup_incremental(
+        &self,
+        dest: impl AsRef<Path>,
+        manifest: &BackupManifest,
+    ) -> Result<BackupManifest, CaskError> {
+        let dest = dest.as_ref();
+
+        let files = {
+            // Merges create and delete data files, keep them from running while the files are
+            // copied
+            let _no_merge = self.inner.compaction.lock().unwrap();
+            self.inner.fs.checkpoint(dest, &manifest.files)?
+        };
+
+        let backup = BackupManifest { files };
+        // The manifest goes last, a backup without one is incomplete
+        backup.store(dest).map_err(FsError::from)?;
+        info!(files = backup.files.len(), "Finished backup");
+
+        Ok(backup)
+    }
+}
+
+/// Rebuilds a data store in the empty directory `dest` from a chain of backups
+///
+/// `chain` lists the backup directories written by [`Cask::backup_incremental`], oldest first. The
+/// data store is restored to the state of the last backup in the chain. Earlier backups only need
+/// to be part of the chain as far as the last one shares files with them.
+#[instrument(skip(chain, dest), fields(dest = ?dest.as_ref()))]
+pub fn restore_backup<P>(chain: &[P], dest: impl AsRef<Path>) -> Result<(), CaskError>
+where
+    P: AsRef<Path>,
+{
+    let dest = dest.as_ref();
+    let Some(last) = chain.last() else {
+        return Err(FsError::from(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No backups to restore from",
+        ))
+        .into());
+    };
+    let manifest = BackupManifest::load(last)?;
+
+    let restore = || -> io::Result<()> {
+        fs::create_dir_all(dest)?;
+        if fs::read_dir(dest)?.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Restore directory {} is not empty", dest.display()),
+            ));
+        }
+
+        for (name, size) in manifest.files() {
+            // Sizes have to match as well, an older backup might hold a shorter copy of what was
+            // the active file back then. Copies of the same size are taken from the newest backup,
+            // which copied the file again if it changed since.
+            let source = chain
+                .iter()
+                .rev()
+                .map(|backup| backup.as_ref().join(name))
+                .find(|path| fs::metadata(path).is_ok_and(|metadata| metadata.len() == size));
+            let Some(source) = source else {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{name} is missing from the backup chain"),
+                ));
+            };
+
+            fs::copy(&source, dest.join(name))?;
+            File::open(dest.join(name))?.sync_all()?;
+        }
+
+        File::open(dest)?.sync_all()
+    };
+    restore().map_err(FsError::from)?;
+
+    info!(files = manifest.files.len(), "Restored backup");
+    Ok(())
+}
@@ -0,0 +1,158 @@
+//! Atomic multi-key writes
+//!
+//! The entries of a batch are written back to back, each flagged as being part of a batch, and
+//! followed by a commit record holding the number of entries. The whole batch goes out in a single
+//! write to the active file. If the process dies before the commit record hits the disk, the
+//! batch is dropped as a whole when the active file is replayed.
+use crate::{
+    apply_entry,
+    repr::{self, Entry},
+    Cask, CaskError, System,
+};
+
+#[derive(Debug, Clone)]
+enum Operation {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+impl Operation {
+    fn key(&self) -> &Vec<u8> {
+        match self {
+            Operation::Put { key, .. } | Operation::Delete { key } => key,
+        }
+    }
+}
+
+/// A set of inserts and removals applied atomically through [`Cask::write`]
+///
+/// Operations are applied in the order they were added, so a later operation on a key wins over
+/// an earlier one.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    operations: Vec<Operation>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Inserts `value` under `key`
+    pub fn put<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.operations.push(Operation::Put {
+            key: key.as_ref().into(),
+            value: value.as_ref().into(),
+        });
+        self
+    }
+
+    /// Removes `key`
+    pub fn delete<K>(&mut self, key: K) -> &mut Self
+    where
+        K: AsRef<[u8]>,
+    {
+        self.operations.push(Operation::Delete {
+            key: key.as_ref().into(),
+        });
+        self
+    }
+
+    /// Number of operations in the batch
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+}
+
+impl<T> Cask<T>
+where
+    T: System,
+{
+    /// Applies every operation of a write batch atomically
+    ///
+    /// Readers either see all of the batch or none of it, and after a crash the batch is either
+    /// fully recovered or dropped.
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{Cask, WriteBatch, test::TestFileSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    ///     let cask: Cask<TestFileSystem> = Cask::new("")?;
+    ///     cask.insert("old", "record")?;
+    ///
+    ///     let mut batch = WriteBatch::new();
+    ///     batch.put("record", "value").put("index", "record").delete("old");
+    ///     cask.write(batch)?;
+    ///
+    ///     assert_eq!(cask.get(&"index")?, "record".as_bytes());
+    ///     assert!(cask.get(&"old").is_err());
+    ///     # Ok(())
+    /// # }
+    /// ```
+    pub fn write(&self, batch: WriteBatch) -> Result<(), CaskError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let _key_locks = self.inner.key_locks.lock_all(
+            batch
+                .operations
+                .iter()
+                .map(|operation| operation.key().as_slice()),
+        );
+
+        let fs = &self.inner.fs;
+        // The whole batch shares one timestamp, so restoring to a point in time never splits it
+        let timestamp = repr::unix_timestamp(fs)?;
+        let values: Vec<_> = batch
+            .operations
+            .iter()
+            .map(|operation| match operation {
+                Operation::Put { value, .. } => Some(self.encode_value(value)),
+                Operation::Delete { .. } => None,
+            })
+            .collect();
+        let mut entries = Vec::with_capacity(batch.len());
+        for (operation, value) in batch.operations.iter().zip(&values) {
+            let entry = match value {
+                Some((value, flags)) => {
+                    Entry::new_encoded(operation.key(), value, fs)?.flagged(*flags)
+                }
+                None => Entry::new_empty(operation.key(), fs)?,
+            };
+            entries.push(entry.stamped(timestamp));
+        }
+        let commit = repr::batch_commit(entries.len() as u32, timestamp);
+        let written = fs.write_batch(entries, &commit)?;
+        for (operation, cache_entry) in batch.operations.iter().zip(&written) {
+            if let Operation::Put { value, .. } = operation {
+                self.inner.cache.insert(cache_entry, value);
+            }
+        }
+
+        // Apply the whole batch under a single lock, so that readers never observe part of it.
+        // The commit record is not tracked as dead bytes, just like on startup.
+        let mut keydir = self.inner.keydir.write().unwrap();
+        let mut stats = self.inner.stats.lock().unwrap();
+        for (operation, cache_entry) in batch.operations.into_iter().zip(written) {
+            match operation {
+                Operation::Put { key, .. } => {
+                    apply_entry(&mut keydir, &mut stats, key, false, cache_entry)
+                }
+                Operation::Delete { key } => {
+                    apply_entry(&mut keydir, &mut stats, key, true, cache_entry)
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
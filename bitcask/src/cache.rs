@@ -0,0 +1,210 @@
+//! Cache of recently used values
+//!
+//! Entries never change once written, so values are cached by the location of their entry and
+//! never go stale. Overwritten values simply stop being read and age out. Only deleting a data
+//! file requires dropping its values, as nothing else would ever evict them.
+//!
+//! Eviction follows the CLOCK algorithm: every read marks the value as referenced, and the hand
+//! sweeping over the cached values gives referenced ones a second chance instead of evicting them.
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    fs::{Fd, Offset},
+    CacheEntry,
+};
+
+/// Hit and miss counters of the value cache
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads served from the cache
+    pub hits: u64,
+    /// Reads which had to go to the data files
+    pub misses: u64,
+    /// Total size of the cached values
+    pub bytes: u64,
+}
+
+impl CacheStats {
+    /// Fraction of the reads served from the cache
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / total as f64
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ValueCache {
+    /// Upper bound of the total size of the cached values, `0` disables the cache
+    capacity: usize,
+    clock: Mutex<Clock>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Clock {
+    /// Position of every cached value in `slots`
+    index: HashMap<(Fd, Offset), usize>,
+    slots: Vec<Slot>,
+    hand: usize,
+    bytes: usize,
+}
+
+#[derive(Debug)]
+struct Slot {
+    location: (Fd, Offset),
+    value: Vec<u8>,
+    referenced: bool,
+}
+
+impl ValueCache {
+    pub fn new(capacity: usize) -> Self {
+        ValueCache {
+            capacity,
+            clock: Mutex::new(Clock::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Cached value of the entry at `cache_entry`
+    pub fn get(&self, cache_entry: &CacheEntry) -> Option<Vec<u8>> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        let mut clock = self.clock.lock().unwrap();
+        let Some(&slot) = clock.index.get(&(cache_entry.fd, cache_entry.offset)) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        let slot = &mut clock.slots[slot];
+        slot.referenced = true;
+        Some(slot.value.clone())
+    }
+
+    /// Caches `value` as the value of the entry at `cache_entry`, evicting others to make room
+    pub fn insert(&self, cache_entry: &CacheEntry, value: &[u8]) {
+        // Values larger than the whole cache would only flush it
+        if self.capacity == 0 || value.len() > self.capacity {
+            return;
+        }
+
+        let location = (cache_entry.fd, cache_entry.offset);
+        let mut clock = self.clock.lock().unwrap();
+        if clock.index.contains_key(&location) {
+            return;
+        }
+
+        while clock.bytes + value.len() > self.capacity {
+            clock.evict();
+        }
+        let slot = clock.slots.len();
+        clock.index.insert(location, slot);
+        clock.slots.push(Slot {
+            location,
+            value: value.into(),
+            referenced: false,
+        });
+        clock.bytes += value.len();
+    }
+
+    /// Drops the values of a deleted data file
+    pub fn remove_file(&self, fd: Fd) {
+        let mut clock = self.clock.lock().unwrap();
+        let mut slot = 0;
+        while slot < clock.slots.len() {
+            if clock.slots[slot].location.0 == fd {
+                clock.remove(slot);
+            } else {
+                slot += 1;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bytes: self.clock.lock().unwrap().bytes as u64,
+        }
+    }
+}
+
+impl Clock {
+    /// Evicts the first value under the hand which has not been referenced since the hand last
+    /// passed it
+    fn evict(&mut self) {
+        loop {
+            if self.hand >= self.slots.len() {
+                self.hand = 0;
+            }
+            let slot = &mut self.slots[self.hand];
+            if !slot.referenced {
+                self.remove(self.hand);
+                return;
+            }
+            slot.referenced = false;
+            self.hand += 1;
+        }
+    }
+
+    /// Removes the value in `slot`, moving the last value into its place
+    fn remove(&mut self, slot: usize) {
+        let removed = self.slots.swap_remove(slot);
+        self.index.remove(&removed.location);
+        self.bytes -= removed.value.len();
+        if let Some(moved) = self.slots.get(slot) {
+            self.index.insert(moved.location, slot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ValueCache;
+    use crate::{
+        fs::{Fd, Offset},
+        CacheEntry,
+    };
+
+    fn entry(fd: usize, offset: usize) -> CacheEntry {
+        CacheEntry {
+            fd: Fd::from_index(fd),
+            value_size: 0,
+            offset: Offset(offset),
+            expires_at: 0,
+        }
+    }
+
+    #[test]
+    fn referenced_values_get_a_second_chance() {
+        let cache = ValueCache::new(30);
+        for offset in 0..3 {
+            cache.insert(&entry(0, offset), &[offset as u8; 10]);
+        }
+        assert!(cache.get(&entry(0, 0)).is_some());
+
+        // The hand skips the value just read and evicts the next one instead
+        cache.insert(&entry(1, 0), &[3; 10]);
+        assert_eq!(cache.get(&entry(0, 0)), Some(vec![0; 10]));
+        assert_eq!(cache.get(&entry(0, 1)), None);
+        assert_eq!(cache.get(&entry(0, 2)), Some(vec![2; 10]));
+        assert_eq!(cache.stats().bytes, 30);
+
+        cache.remove_file(Fd::from_index(0));
+        assert_eq!(cache.get(&entry(0, 0)), None);
+        assert_eq!(cache.get(&entry(1, 0)), Some(vec![3; 10]));
+        assert_eq!(cache.stats().bytes, 10);
+    }
+}
@@ -1,102 +1,226 @@
 //! Sans-io state machine controlling the compaction loop
 //!
 //! The compactor performs the following set of operations:
-//! - Loop on an immutable file
+//! - Loop on the immutable files
 //! - Check if entry is present in KeyDir
 //!     - If it's present,
 //!         - and it's timestamp is the same as the one in the keydir entry
 //!         - and it's location matches KeyDir location, move it to compacted file.
 //!         - Also create a new hintfile entry
 //!     - If it is not the same location, ignore this entry
-//!     - If it's a tombstone entry, ignore
+//!     - If it's a tombstone entry, ignore, unless older files are left out of the merge
+//! - Once every file has been read, commit the compacted file, point the KeyDir at it and delete
+//!   the old files.
+//!
+//! In the background, the compactor periodically asks for the fragmentation of the immutable files
+//! and only starts a merge once enough space can be reclaimed, see [`Triggers`].
+//!
+//! The compactor itself does not perform any IO. The driver polls it for [`Operation`]s to
+//! perform, and reports back the outcome of those operations as [`Input`]s.
 use std::{
     collections::VecDeque,
     time::{Duration, Instant},
 };
 
-use crate::repr::{Entry, Header};
+use crate::Config;
+
+/// Thresholds deciding when a merge pays off
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Triggers {
+    /// Merge once a single immutable file has at least this fraction of dead bytes
+    pub dead_ratio: f64,
+    /// Merge once the immutable files hold at least this many dead bytes in total
+    pub dead_bytes: u64,
+    /// Time to wait between two fragmentation checks
+    pub check_interval: Duration,
+}
+
+impl From<&Config> for Triggers {
+    fn from(config: &Config) -> Self {
+        Triggers {
+            dead_ratio: config.merge_dead_ratio,
+            dead_bytes: config.merge_dead_bytes,
+            check_interval: config.merge_check_interval,
+        }
+    }
+}
+
+impl Default for Triggers {
+    fn default() -> Self {
+        Triggers::from(&Config::default())
+    }
+}
 
 enum State {
     /// Stores the instant when we went into the wait state, along with the current instant
     Wait(Instant),
 
+    /// Waiting on the fragmentation of the immutable files
+    Check,
+
     /// Compact state
     Compact,
 }
 
-#[derive(Debug)]
-pub(crate) enum Operation<'entry> {
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Operation {
+    /// Report how much space a merge would reclaim
+    CheckFragmentation,
+    /// Skip the current entry
     Ignore,
+    /// Pick the immutable files that are going to be merged
     CheckFile,
-    CheckKeydir(&'entry [u8]),
+    /// Read the next entry from the files being merged
+    NextEntry,
+    /// Check whether the KeyDir still points at the current entry
+    CheckKeydir,
+    /// Copy the current entry into the merged file
     AddImmutable,
+    /// Record the hint for the entry copied last
     AddHint,
+    /// Persist the merged file, update the KeyDir and delete the merged files
+    Commit,
 }
 
-pub(crate) struct Compactor<'entry> {
-    operations: VecDeque<Operation<'entry>>,
+pub(crate) struct Compactor {
+    operations: VecDeque<Operation>,
     state: State,
+    triggers: Triggers,
+    /// Whether tombstones are copied into the merged file
+    keep_tombstones: bool,
 }
 
-pub(crate) enum Input<'file> {
-    Entry(Entry<'file>),
+pub(crate) enum Input {
+    /// Dead bytes across all immutable files, and the highest dead ratio of any single one of them
+    Fragmentation {
+        dead_bytes: u64,
+        dead_ratio: f64,
+        now: Instant,
+    },
+    /// The files to merge have been picked. Tombstones have to be kept if older files holding
+    /// values they shadow are not part of the merge.
+    Files {
+        keep_tombstones: bool,
+    },
+    Entry {
+        tombstone: bool,
+    },
     End(Instant),
     MatchKeydir,
     NotMatchkeydir,
 }
 
-impl<'entry> Compactor<'entry> {
+impl Compactor {
+    /// Creates a compactor which merges right away, regardless of fragmentation
     pub fn new() -> Self {
         let mut queue = VecDeque::new();
         queue.push_back(Operation::CheckFile);
+        queue.push_back(Operation::NextEntry);
         Self {
             operations: queue,
             // When compactor is initialized, we start in the loop state and are ready to issue a
             // CheckFile request as soon as we are polled.
             state: State::Compact,
+            triggers: Triggers::default(),
+            keep_tombstones: false,
         }
     }
 
-    pub fn handle_input(&mut self, input: Input<'entry>) {
+    /// Creates a compactor which only merges once one of the `triggers` fires
+    pub fn background(triggers: Triggers, now: Instant) -> Self {
+        Self {
+            operations: VecDeque::new(),
+            state: State::Wait(now),
+            triggers,
+            keep_tombstones: false,
+        }
+    }
+
+    fn start_merge(&mut self) {
+        self.operations.push_back(Operation::CheckFile);
+        self.operations.push_back(Operation::NextEntry);
+        self.state = State::Compact;
+    }
+
+    pub fn handle_input(&mut self, input: Input) {
         match self.state {
             // Don't need to do anything in this state.
             State::Wait(_at) => {}
 
+            State::Check => {
+                if let Input::Fragmentation {
+                    dead_bytes,
+                    dead_ratio,
+                    now,
+                } = input
+                {
+                    if dead_bytes > 0
+                        && (dead_bytes >= self.triggers.dead_bytes
+                            || dead_ratio >= self.triggers.dead_ratio)
+                    {
+                        self.start_merge();
+                    } else {
+                        self.state = State::Wait(now);
+                    }
+                }
+            }
+
             State::Compact => {
                 // If the file exists and entries are present, we are actively compacting
                 match input {
-                    Input::Entry(entry) => {
-                        //if entry.is_tombstone() {
-                        //    self.operations.push_back(Operation::Ignore);
-                        //} else {
-                        //    self.operations
-                        //        .push_back(Operation::CheckKeydir(entry.key()));
-                        //}
+                    Input::Files { keep_tombstones } => self.keep_tombstones = keep_tombstones,
+                    Input::Entry { tombstone } => {
+                        if tombstone && self.keep_tombstones {
+                            self.operations.push_back(Operation::AddImmutable);
+                            self.operations.push_back(Operation::AddHint);
+                            self.operations.push_back(Operation::NextEntry);
+                        } else if tombstone {
+                            // Tombstones only need to shadow values in older files, all of which
+                            // are part of the merge.
+                            self.operations.push_back(Operation::Ignore);
+                            self.operations.push_back(Operation::NextEntry);
+                        } else {
+                            self.operations.push_back(Operation::CheckKeydir);
+                        }
                     }
                     Input::MatchKeydir => {
                         self.operations.push_back(Operation::AddImmutable);
-                        self.operations.push_back(Operation::AddHint)
+                        self.operations.push_back(Operation::AddHint);
+                        self.operations.push_back(Operation::NextEntry);
                     }
-                    Input::NotMatchkeydir => self.operations.push_back(Operation::Ignore),
-                    // We're reached the end of the file, switch to the wait state
-                    Input::End(now) => self.state = State::Wait(now),
+                    Input::NotMatchkeydir => {
+                        self.operations.push_back(Operation::Ignore);
+                        self.operations.push_back(Operation::NextEntry);
+                    }
+                    // We're reached the end of the files, commit and switch to the wait state
+                    Input::End(now) => {
+                        self.operations.push_back(Operation::Commit);
+                        self.state = State::Wait(now);
+                    }
+                    Input::Fragmentation { .. } => {}
                 }
             }
         }
     }
 
+    /// Abandons the current merge, fragmentation is checked again after the usual interval
+    pub fn abort(&mut self, now: Instant) {
+        self.operations.clear();
+        self.state = State::Wait(now);
+    }
+
     pub fn handle_timeout(&mut self, now: Instant) {
         let last_sleep = match self.state {
             State::Wait(at) => at,
-            State::Compact => return,
+            State::Check | State::Compact => return,
         };
 
-        if last_sleep.duration_since(now) < Duration::from_secs(60 * 60) {
+        if now.duration_since(last_sleep) < self.triggers.check_interval {
             return;
         }
 
-        self.operations.push_back(Operation::CheckFile);
-        self.state = State::Compact;
+        self.operations.push_back(Operation::CheckFragmentation);
+        self.state = State::Check;
     }
 
     pub fn poll_transmit(&mut self) -> Option<Operation> {
@@ -105,8 +229,123 @@ impl<'entry> Compactor<'entry> {
 
     pub fn poll_timeout(&self) -> Option<Instant> {
         match self.state {
-            State::Compact => None,
-            State::Wait(at) => Some(at + Duration::from_secs(60 * 60)),
+            State::Check | State::Compact => None,
+            State::Wait(at) => Some(at + self.triggers.check_interval),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Compactor, Input, Operation, Triggers};
+
+    const INTERVAL: Duration = Duration::from_secs(60);
+
+    fn triggers() -> Triggers {
+        Triggers {
+            dead_ratio: 0.5,
+            dead_bytes: 1024,
+            check_interval: INTERVAL,
+        }
+    }
+
+    #[test]
+    fn live_entries_are_copied_and_dead_ones_skipped() {
+        let mut compactor = Compactor::new();
+        let now = Instant::now();
+
+        assert_eq!(compactor.poll_transmit(), Some(Operation::CheckFile));
+        assert_eq!(compactor.poll_transmit(), Some(Operation::NextEntry));
+
+        compactor.handle_input(Input::Entry { tombstone: false });
+        assert_eq!(compactor.poll_transmit(), Some(Operation::CheckKeydir));
+        compactor.handle_input(Input::MatchKeydir);
+        assert_eq!(compactor.poll_transmit(), Some(Operation::AddImmutable));
+        assert_eq!(compactor.poll_transmit(), Some(Operation::AddHint));
+        assert_eq!(compactor.poll_transmit(), Some(Operation::NextEntry));
+
+        compactor.handle_input(Input::Entry { tombstone: true });
+        assert_eq!(compactor.poll_transmit(), Some(Operation::Ignore));
+        assert_eq!(compactor.poll_transmit(), Some(Operation::NextEntry));
+
+        compactor.handle_input(Input::End(now));
+        assert_eq!(compactor.poll_transmit(), Some(Operation::Commit));
+        assert_eq!(compactor.poll_transmit(), None);
+    }
+
+    #[test]
+    fn tombstones_are_kept_when_older_files_are_left_out() {
+        let mut compactor = Compactor::new();
+
+        assert_eq!(compactor.poll_transmit(), Some(Operation::CheckFile));
+        compactor.handle_input(Input::Files {
+            keep_tombstones: true,
+        });
+        assert_eq!(compactor.poll_transmit(), Some(Operation::NextEntry));
+
+        compactor.handle_input(Input::Entry { tombstone: true });
+        assert_eq!(compactor.poll_transmit(), Some(Operation::AddImmutable));
+        assert_eq!(compactor.poll_transmit(), Some(Operation::AddHint));
+        assert_eq!(compactor.poll_transmit(), Some(Operation::NextEntry));
+    }
+
+    #[test]
+    fn merges_only_once_a_trigger_fires() {
+        let now = Instant::now();
+        let mut compactor = Compactor::background(triggers(), now);
+        assert_eq!(compactor.poll_transmit(), None);
+        assert_eq!(compactor.poll_timeout(), Some(now + INTERVAL));
+
+        compactor.handle_timeout(now);
+        assert_eq!(compactor.poll_transmit(), None);
+
+        // Below both thresholds, go back to waiting
+        let now = now + INTERVAL;
+        compactor.handle_timeout(now);
+        assert_eq!(
+            compactor.poll_transmit(),
+            Some(Operation::CheckFragmentation)
+        );
+        assert_eq!(compactor.poll_timeout(), None);
+        compactor.handle_input(Input::Fragmentation {
+            dead_bytes: 100,
+            dead_ratio: 0.1,
+            now,
+        });
+        assert_eq!(compactor.poll_transmit(), None);
+        assert_eq!(compactor.poll_timeout(), Some(now + INTERVAL));
+
+        // A single badly fragmented file is enough
+        let now = now + INTERVAL;
+        compactor.handle_timeout(now);
+        assert_eq!(
+            compactor.poll_transmit(),
+            Some(Operation::CheckFragmentation)
+        );
+        compactor.handle_input(Input::Fragmentation {
+            dead_bytes: 100,
+            dead_ratio: 0.6,
+            now,
+        });
+        assert_eq!(compactor.poll_transmit(), Some(Operation::CheckFile));
+        assert_eq!(compactor.poll_transmit(), Some(Operation::NextEntry));
+        compactor.handle_input(Input::End(now));
+        assert_eq!(compactor.poll_transmit(), Some(Operation::Commit));
+
+        // So are enough dead bytes spread across files
+        let now = now + INTERVAL;
+        compactor.handle_timeout(now);
+        assert_eq!(
+            compactor.poll_transmit(),
+            Some(Operation::CheckFragmentation)
+        );
+        compactor.handle_input(Input::Fragmentation {
+            dead_bytes: 2048,
+            dead_ratio: 0.2,
+            now,
+        });
+        assert_eq!(compactor.poll_transmit(), Some(Operation::CheckFile));
+    }
+}
@@ -0,0 +1,60 @@
+//! Value compression
+//!
+//! Values are compressed one entry at a time, and the codec an entry was written with is recorded
+//! in the flags of its header. Files holding entries written under different configurations
+//! therefore always decode, and changing the codec only affects new writes.
+use std::{backtrace::Backtrace, borrow::Cow};
+
+use crate::repr::Header;
+
+/// Codec values are compressed with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Store values as they are
+    #[default]
+    None,
+
+    /// LZ4 block compression, fast at a moderate ratio
+    Lz4,
+}
+
+impl Compression {
+    /// Encodes `value` for storage, returning the stored bytes along with the header flags
+    /// describing them
+    ///
+    /// Values smaller than `min_size`, or which would not shrink, are stored as they are.
+    pub(crate) fn encode(self, value: &[u8], min_size: usize) -> (Cow<'_, [u8]>, u8) {
+        if value.len() < min_size {
+            return (Cow::Borrowed(value), 0);
+        }
+
+        let (compressed, flag) = match self {
+            Compression::None => return (Cow::Borrowed(value), 0),
+            Compression::Lz4 => (lz4_flex::compress_prepend_size(value), Header::LZ4),
+        };
+
+        if compressed.len() >= value.len() {
+            return (Cow::Borrowed(value), 0);
+        }
+        (Cow::Owned(compressed), flag)
+    }
+}
+
+/// Restores a value stored with the header flags `flags`
+pub(crate) fn decode(flags: u8, stored: &[u8]) -> Result<Vec<u8>, CompressionError> {
+    if flags & Header::LZ4 != 0 {
+        return Ok(lz4_flex::decompress_size_prepended(stored)?);
+    }
+
+    Ok(stored.into())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CompressionError {
+    #[error("Unable to decompress LZ4 value: {source}")]
+    Lz4 {
+        #[from]
+        source: lz4_flex::block::DecompressError,
+        backtrace: Backtrace,
+    },
+}
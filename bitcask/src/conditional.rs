@@ -0,0 +1,137 @@
+//! Conditional writes
+//!
+//! The condition is checked and the write performed while holding the lock of the key, so no
+//! other write to the key can get in between, neither in the KeyDir nor in the log. A write whose
+//! condition does not hold fails with [`CaskError::PreconditionFailed`] and leaves the key alone.
+use crate::{Cask, CaskError, System};
+
+/// What a key has to look like for a conditional write to go ahead
+enum Expected<'a> {
+    Absent,
+    Value(&'a [u8]),
+}
+
+impl<T> Cask<T>
+where
+    T: System,
+{
+    /// Inserts `value` under `key`, unless the key already exists
+    ///
+    /// Fails with [`CaskError::PreconditionFailed`] if the key exists.
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{Cask, CaskError, test::TestFileSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    ///     let cask: Cask<TestFileSystem> = Cask::new("")?;
+    ///     cask.insert_if_absent("hello", "world")?;
+    ///     assert!(matches!(
+    ///         cask.insert_if_absent("hello", "there"),
+    ///         Err(CaskError::PreconditionFailed)
+    ///     ));
+    ///     assert_eq!(cask.get(&"hello")?, "world".as_bytes());
+    ///     # Ok(())
+    /// # }
+    /// ```
+    pub fn insert_if_absent<K, V>(&self, key: K, value: V) -> Result<(), CaskError>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        self.write_if(key, Expected::Absent, || {
+            self.put_locked(key, value.as_ref(), None)
+        })
+    }
+
+    /// Replaces the value of `key` with `new`, if its current value is `expected`
+    ///
+    /// Fails with [`CaskError::PreconditionFailed`] if the value differs. A key that does not
+    /// exist never matches.
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{Cask, CaskError, test::TestFileSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    ///     let cask: Cask<TestFileSystem> = Cask::new("")?;
+    ///     cask.insert("counter", "1")?;
+    ///     cask.compare_and_swap("counter", "1", "2")?;
+    ///     assert!(matches!(
+    ///         cask.compare_and_swap("counter", "1", "3"),
+    ///         Err(CaskError::PreconditionFailed)
+    ///     ));
+    ///     assert_eq!(cask.get(&"counter")?, "2".as_bytes());
+    ///     # Ok(())
+    /// # }
+    /// ```
+    pub fn compare_and_swap<K, E, V>(&self, key: K, expected: E, new: V) -> Result<(), CaskError>
+    where
+        K: AsRef<[u8]>,
+        E: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        self.write_if(key, Expected::Value(expected.as_ref()), || {
+            self.put_locked(key, new.as_ref(), None)
+        })
+    }
+
+    /// Removes `key`, if its current value is `expected`
+    ///
+    /// Fails with [`CaskError::PreconditionFailed`] if the value differs or the key does not
+    /// exist.
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{Cask, CaskError, test::TestFileSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    ///     let cask: Cask<TestFileSystem> = Cask::new("")?;
+    ///     cask.insert("lock", "owner-1")?;
+    ///     assert!(matches!(
+    ///         cask.remove_if("lock", "owner-2"),
+    ///         Err(CaskError::PreconditionFailed)
+    ///     ));
+    ///     cask.remove_if("lock", "owner-1")?;
+    ///     assert!(cask.get(&"lock").is_err());
+    ///     # Ok(())
+    /// # }
+    /// ```
+    pub fn remove_if<K, E>(&self, key: K, expected: E) -> Result<(), CaskError>
+    where
+        K: AsRef<[u8]>,
+        E: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        self.write_if(key, Expected::Value(expected.as_ref()), || {
+            self.delete_locked(key)
+        })
+    }
+
+    /// Performs `write` if `key` is in the `expected` state
+    ///
+    /// Returns [`CaskError::PreconditionFailed`] if it is not.
+    fn write_if<F>(&self, key: &[u8], expected: Expected<'_>, write: F) -> Result<(), CaskError>
+    where
+        F: FnOnce() -> Result<(), CaskError>,
+    {
+        let _key_lock = self.inner.key_locks.lock(key);
+
+        let holds = {
+            let keydir = self.inner.keydir.read().unwrap();
+            match (expected, keydir.get_live(key, self.now())) {
+                (Expected::Absent, current) => current.is_none(),
+                (Expected::Value(_), None) => false,
+                // The stored size of a compressed value says nothing about its length, so
+                // always compare the values themselves
+                (Expected::Value(expected), Some(cache_entry)) => {
+                    self.read_value(&cache_entry)? == expected
+                }
+            }
+        };
+
+        if !holds {
+            return Err(CaskError::PreconditionFailed);
+        }
+        write()
+    }
+}
@@ -0,0 +1,165 @@
+//! Encryption at rest
+//!
+//! With encryption enabled, the key and value of every entry are sealed together with
+//! XChaCha20-Poly1305. Sealed data starts with a [`CipherHeader`] naming the key it was sealed
+//! with along with the random nonce, followed by the ciphertext and its authentication tag. Entry
+//! headers stay in the clear, so that data files can still be scanned and checksummed without the
+//! keys. Hint files are sealed as a whole in the same way.
+//!
+//! Keys are identified by a number, which allows rotating them: entries sealed with an older key
+//! stay readable as long as the key is configured, and merges seal the live entries they copy
+//! with the current key.
+use std::{collections::HashMap, fmt, mem};
+
+use bytemuck::{bytes_of, Pod, Zeroable};
+use chacha20poly1305::{
+    aead::{AeadCore, AeadInPlace, KeyInit, OsRng},
+    Tag, XChaCha20Poly1305, XNonce,
+};
+
+/// Length of the authentication tag following the ciphertext
+const TAG_LEN: usize = 16;
+
+/// Precedes the ciphertext of sealed data
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+struct CipherHeader {
+    /// Id of the key the data was sealed with
+    key_id: u32,
+    nonce: [u8; 24],
+}
+
+impl CipherHeader {
+    const LEN: usize = mem::size_of::<CipherHeader>();
+}
+
+/// Keys used to encrypt the data store
+///
+/// New data is always sealed with the current key, older keys are only used to open what was
+/// sealed with them.
+#[derive(Clone)]
+pub struct Encryption {
+    current: u32,
+    keys: HashMap<u32, [u8; 32]>,
+}
+
+impl Encryption {
+    /// Number of bytes sealing adds to the data
+    pub(crate) const OVERHEAD: usize = CipherHeader::LEN + TAG_LEN;
+
+    /// Encrypts with the 256 bit `key`, which is known as `id`
+    ///
+    /// The id is stored along with everything sealed, and selects the key to open it with later
+    /// on. Never reuse an id for a different key.
+    pub fn new(id: u32, key: [u8; 32]) -> Self {
+        Encryption {
+            current: id,
+            keys: HashMap::from([(id, key)]),
+        }
+    }
+
+    /// Keeps data sealed with an older `key`, known as `id`, readable
+    ///
+    /// Merges re-encrypt the live entries they copy with the current key. Once every data file
+    /// has been merged since the rotation, the older key is no longer needed.
+    pub fn with_old_key(mut self, id: u32, key: [u8; 32]) -> Self {
+        self.keys.entry(id).or_insert(key);
+        self
+    }
+
+    /// Seals the concatenation of `parts` with the current key
+    pub(crate) fn seal(&self, parts: &[&[u8]]) -> Vec<u8> {
+        let header = CipherHeader {
+            key_id: self.current,
+            nonce: XChaCha20Poly1305::generate_nonce(&mut OsRng).into(),
+        };
+
+        let len = parts.iter().map(|part| part.len()).sum::<usize>();
+        let mut sealed = Vec::with_capacity(len + Encryption::OVERHEAD);
+        sealed.extend_from_slice(bytes_of(&header));
+        for part in parts {
+            sealed.extend_from_slice(part);
+        }
+
+        // Entries are far below the message size limit of the cipher
+        let tag = self
+            .cipher(self.current)
+            .expect("The current key is always known")
+            .encrypt_in_place_detached(
+                XNonce::from_slice(&header.nonce),
+                bytes_of(&header),
+                &mut sealed[CipherHeader::LEN..],
+            )
+            .expect("Data exceeds the message size limit");
+        sealed.extend_from_slice(&tag);
+
+        sealed
+    }
+
+    /// Opens data sealed with any of the known keys
+    pub(crate) fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let header = Encryption::header(sealed).ok_or(EncryptionError::Decrypt)?;
+        let (ciphertext, tag) = sealed[CipherHeader::LEN..].split_at(
+            sealed
+                .len()
+                .checked_sub(CipherHeader::LEN + TAG_LEN)
+                .ok_or(EncryptionError::Decrypt)?,
+        );
+
+        let key_id = header.key_id;
+        let cipher = self
+            .cipher(key_id)
+            .ok_or(EncryptionError::UnknownKey { id: key_id })?;
+
+        let mut plaintext = ciphertext.to_vec();
+        cipher
+            .decrypt_in_place_detached(
+                XNonce::from_slice(&header.nonce),
+                bytes_of(&header),
+                &mut plaintext,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| EncryptionError::Decrypt)?;
+
+        Ok(plaintext)
+    }
+
+    /// Whether `sealed` was sealed with the current key
+    pub(crate) fn is_current(&self, sealed: &[u8]) -> bool {
+        Encryption::header(sealed).is_some_and(|header| header.key_id == self.current)
+    }
+
+    fn header(sealed: &[u8]) -> Option<CipherHeader> {
+        let header = sealed.get(..CipherHeader::LEN)?;
+        bytemuck::try_pod_read_unaligned(header).ok()
+    }
+
+    fn cipher(&self, id: u32) -> Option<XChaCha20Poly1305> {
+        let key = self.keys.get(&id)?;
+        Some(XChaCha20Poly1305::new(key.into()))
+    }
+}
+
+// Keep the keys out of logs
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ids: Vec<_> = self.keys.keys().collect();
+        ids.sort();
+        f.debug_struct("Encryption")
+            .field("current", &self.current)
+            .field("keys", &ids)
+            .finish()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EncryptionError {
+    #[error("Data is encrypted, but no encryption is configured")]
+    NotConfigured,
+
+    #[error("Data is encrypted with unknown key {id}")]
+    UnknownKey { id: u32 },
+
+    #[error("Unable to decrypt data, it has been tampered with or the key is wrong")]
+    Decrypt,
+}
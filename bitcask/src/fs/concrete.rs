@@ -1,35 +1,83 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     fs::{self, File, OpenOptions},
-    io::{self, Write},
+    io::{self, IoSlice, Write},
     os::unix::fs::FileExt,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::atomic::{AtomicUsize, Ordering},
+    time::{Instant, SystemTime},
 };
 
-use tracing::{instrument, trace};
+use tracing::{info, instrument, trace};
 
 use crate::{ClockSource, FileSystem, System};
 
 use super::{Fd, FsError};
 
+const ACTIVE_FILE: &str = "active.db";
+
+/// Size of the chunks the active file is copied in when writing a checkpoint
+const COPY_CHUNK: usize = 64 * 1024;
+
 /// Implements the FileSystem interface for an actual system.
 ///
 /// This structure does not need to be threadsafe as it is used within the `Fs` struct and wrapped
 /// with a lock there.
+///
+/// The active file is always called `active.db`. When it is rotated, it gets renamed to
+/// `immutable-{fd}.db`, which keeps the Fds of data files stable across restarts. The output of a
+/// merge is named after the newest file that went into it, `immutable-{fd}-{seq}.db`, so that it
+/// sorts right after its inputs and before every file written after the merge started.
 pub struct ConcreteSystem {
     fd_num: AtomicUsize,
     active: Fd,
     map: HashMap<Fd, File>,
+    /// Name of every data file, the active file uses the name it will get once rotated
+    ids: HashMap<Fd, FileId>,
+    /// Data files ordered from oldest to newest, the active file is always last
+    files: Vec<Fd>,
+    /// Merged files which have not been committed yet
+    merging: HashSet<Fd>,
     cask_path: PathBuf,
 }
 
+/// Identifies a data file on disk, ordered by the age of its contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct FileId {
+    generation: usize,
+    merge: usize,
+}
+
+impl FileId {
+    /// Parses file names of the form `immutable-{generation}.db` or
+    /// `immutable-{generation}-{merge}.db`
+    fn parse(name: &str) -> Option<FileId> {
+        let id = name.strip_prefix("immutable-")?.strip_suffix(".db")?;
+        let (generation, merge) = match id.split_once('-') {
+            Some((generation, merge)) => (generation.parse().ok()?, merge.parse().ok()?),
+            None => (id.parse().ok()?, 0),
+        };
+        Some(FileId { generation, merge })
+    }
+
+    /// File name without the extension
+    fn stem(&self) -> String {
+        match self.merge {
+            0 => format!("immutable-{}", self.generation),
+            merge => format!("immutable-{}-{merge}", self.generation),
+        }
+    }
+}
+
 impl ConcreteSystem {
     fn new(cask_path: impl Into<PathBuf>) -> Self {
         ConcreteSystem {
             fd_num: AtomicUsize::new(1),
             active: Fd(1),
             map: HashMap::new(),
+            ids: HashMap::new(),
+            files: Vec::new(),
+            merging: HashSet::new(),
             cask_path: cask_path.into(),
         }
     }
@@ -38,49 +86,181 @@ impl ConcreteSystem {
         Fd(self.fd_num.fetch_add(1, Ordering::Relaxed))
     }
 
-    fn create_or_swap_active(&mut self) -> Result<Fd, FsError> {
-        let has_active = fs::read_dir(self.cask_path.clone())?
-            .any(|entry| entry.map_or(false, |entry| entry.file_name() == "active.db"));
+    fn not_found(file: Fd) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Unable to fine file with fd: {}", file),
+        )
+    }
+
+    fn id(&self, file: Fd) -> io::Result<FileId> {
+        self.ids
+            .get(&file)
+            .copied()
+            .ok_or_else(|| ConcreteSystem::not_found(file))
+    }
+
+    /// Open handle of the given data file
+    pub(super) fn file(&self, file: Fd) -> io::Result<&File> {
+        self.map
+            .get(&file)
+            .ok_or_else(|| ConcreteSystem::not_found(file))
+    }
+
+    fn data_path(&self, file: Fd) -> io::Result<PathBuf> {
+        if file == self.active {
+            return Ok(self.cask_path.join(ACTIVE_FILE));
+        }
+
+        let stem = self.id(file)?.stem();
+        if self.merging.contains(&file) {
+            Ok(self.cask_path.join(format!("{stem}.db.merge")))
+        } else {
+            Ok(self.cask_path.join(format!("{stem}.db")))
+        }
+    }
+
+    /// Persists the directory entries of files created, renamed or removed in the data directory
+    fn sync_dir(&self) -> io::Result<()> {
+        File::open(&self.cask_path)?.sync_all()
+    }
+
+    fn hint_path(&self, file: Fd) -> io::Result<PathBuf> {
+        Ok(self
+            .cask_path
+            .join(format!("{}.hint", self.id(file)?.stem())))
+    }
+
+    /// Reopens the immutable files left behind by a previous instance, oldest first.
+    ///
+    /// Leftovers of merges or hint files that never completed are skipped, and removed if
+    /// `remove_leftovers` is set.
+    fn open_immutable(&mut self, remove_leftovers: bool) -> Result<(), FsError> {
+        let mut immutable = Vec::new();
+        for entry in fs::read_dir(&self.cask_path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+
+            if name.ends_with(".merge") || name.ends_with(".tmp") {
+                if remove_leftovers {
+                    info!(path = ?entry.path(), "Removing leftovers of an interrupted write");
+                    fs::remove_file(entry.path())?;
+                }
+                continue;
+            }
+
+            if let Some(id) = FileId::parse(&name) {
+                immutable.push((id, entry.path()));
+            }
+        }
+        immutable.sort_by_key(|(id, _)| *id);
 
-        if has_active {
-            // we have an existing active file, move it into immutable
-            let current_active = self.cask_path.join("active.db");
-            let active_fd = self.active().0;
-            let new_immutable = self.cask_path.join(format!("immutable-{active_fd}.db"));
+        // Rotated files keep their generation as their Fd. Newly created files, including the
+        // active file, need to sort after all of them.
+        let last_generation = immutable.iter().map(|(id, _)| id.generation).max();
+        if let Some(last) = last_generation {
+            self.fd_num.store(last + 1, Ordering::Relaxed);
+        }
+        self.active = self.next_fd();
 
-            fs::rename(current_active, &new_immutable)?;
-            let fd = self.next_fd();
-            let new_immutable_file = File::open(new_immutable)?;
-            self.map.insert(fd, new_immutable_file);
+        for (id, path) in immutable {
+            let fd = match id.merge {
+                0 => Fd(id.generation),
+                _ => self.next_fd(),
+            };
+            trace!(fd = ?fd, path = ?path, "Opening immutable file");
+            self.map.insert(fd, File::open(path)?);
+            self.ids.insert(fd, id);
+            self.files.push(fd);
         }
 
-        // Create new active file
-        let active_path = self.cask_path.join("active.db");
+        Ok(())
+    }
+
+    /// Opens the active file under the current active Fd, creating it if it does not exist yet.
+    fn open_active(&mut self) -> Result<Fd, FsError> {
+        let active_path = self.cask_path.join(ACTIVE_FILE);
         let file = OpenOptions::new()
             .create(true)
+            .truncate(false)
             .read(true)
             .write(true)
-            .open(dbg!(active_path))?;
+            .open(active_path)?;
+        self.sync_dir()?;
 
-        let fd = self.next_fd();
+        Ok(self.insert_active(file))
+    }
+
+    /// Makes `file` addressable as the active file
+    fn insert_active(&mut self, file: File) -> Fd {
+        let fd = self.active;
         self.map.insert(fd, file);
+        self.ids.insert(
+            fd,
+            FileId {
+                generation: fd.0,
+                merge: 0,
+            },
+        );
+        self.files.push(fd);
+        fd
+    }
 
-        self.active = fd;
+    fn swap_active(&mut self) -> Result<Fd, FsError> {
+        // Move the current active file into immutable. The file keeps its Fd so that entries
+        // already pointing into it remain readable.
+        let current_active = self.cask_path.join(ACTIVE_FILE);
+        let active_fd = self.active();
+        self.active = self.next_fd();
+        let new_immutable = self.data_path(active_fd)?;
 
-        Ok(fd)
+        fs::rename(current_active, &new_immutable)?;
+        let new_immutable_file = File::open(new_immutable)?;
+        self.map.insert(active_fd, new_immutable_file);
+
+        // Syncs the directory, which persists the rename along with the new active file
+        self.open_active()
+    }
+}
+
+/// Hard links `from` to `to`, or copies it if the two live on different file systems
+fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::hard_link(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => fs::copy(from, to).map(|_| ()),
+        result => result,
     }
 }
 
 impl FileSystem for ConcreteSystem {
     fn init(path: impl Into<PathBuf>) -> Result<Self, FsError> {
         let mut system = ConcreteSystem::new(path);
-        system.new_active()?;
+        fs::create_dir_all(&system.cask_path)?;
+
+        system.open_immutable(true)?;
+        system.open_active()?;
+
+        Ok(system)
+    }
+
+    fn init_read_only(path: impl Into<PathBuf>) -> Result<Self, FsError> {
+        let mut system = ConcreteSystem::new(path);
+        system.open_immutable(false)?;
+
+        // Copies like checkpoints don't have an active file
+        match File::open(system.cask_path.join(ACTIVE_FILE)) {
+            Ok(file) => {
+                system.insert_active(file);
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
 
         Ok(system)
     }
 
     fn new_active(&mut self) -> Result<Fd, FsError> {
-        Ok(self.create_or_swap_active()?)
+        self.swap_active()
     }
 
     #[instrument(skip(self, buf))]
@@ -89,10 +269,16 @@ impl FileSystem for ConcreteSystem {
             trace!(file = ?file, write_size = buf.len(), "Writing buf into file");
             return file.write_at(buf, offset);
         }
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("Unable to fine file with fd: {}", file),
-        ))
+        Err(ConcreteSystem::not_found(file))
+    }
+
+    #[instrument(skip(self, bufs))]
+    fn write_vectored_at(&self, file: Fd, bufs: &[IoSlice<'_>], offset: u64) -> io::Result<usize> {
+        if let Some(file) = self.map.get(&file) {
+            trace!(file = ?file, buffers = bufs.len(), "Writing buffers into file");
+            return file.write_vectored_at(bufs, offset);
+        }
+        Err(ConcreteSystem::not_found(file))
     }
 
     #[instrument(skip(self, buf))]
@@ -101,10 +287,7 @@ impl FileSystem for ConcreteSystem {
             trace!(file = ?file, read_size = buf.len(), "Reading into buf from file");
             return file.read_exact_at(buf, offset);
         }
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("Unable to fine file with fd: {}", file),
-        ))
+        Err(ConcreteSystem::not_found(file))
     }
 
     #[instrument(skip(self))]
@@ -113,30 +296,238 @@ impl FileSystem for ConcreteSystem {
             trace!("Reading metadata for active file");
             return Ok(file.metadata()?.len());
         }
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("Unable to fine file with fd: {}", file),
-        ))
+        Err(ConcreteSystem::not_found(file))
     }
 
     #[instrument(skip(self))]
     fn flush(&mut self, file: Fd) -> io::Result<()> {
         if let Some(file) = self.map.get_mut(&file) {
             trace!("Flushing to disk");
-            return Ok(file.flush()?);
+            return file.flush();
         }
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("Unable to fine file with fd: {}", file),
-        ))
+        Err(ConcreteSystem::not_found(file))
+    }
+
+    #[instrument(skip(self))]
+    fn sync(&mut self, file: Fd) -> io::Result<()> {
+        if let Some(file) = self.map.get(&file) {
+            trace!("Syncing to disk");
+            return file.sync_data();
+        }
+        Err(ConcreteSystem::not_found(file))
+    }
+
+    #[instrument(skip(self))]
+    fn truncate(&mut self, file: Fd, len: u64) -> io::Result<()> {
+        if let Some(file) = self.map.get(&file) {
+            trace!("Truncating file");
+            return file.set_len(len);
+        }
+        Err(ConcreteSystem::not_found(file))
+    }
+
+    #[instrument(skip(self, buf), fields(hint_size = buf.len()))]
+    fn write_hint(&mut self, file: Fd, buf: &[u8]) -> io::Result<()> {
+        // Write to a temporary file first, so that a crash never leaves a partial hint file behind
+        let path = self.hint_path(file)?;
+        let tmp_path = path.with_extension("hint.tmp");
+        fs::write(&tmp_path, buf)?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        self.sync_dir()
+    }
+
+    #[instrument(skip(self))]
+    fn read_hint(&self, file: Fd) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.hint_path(file)?) {
+            Ok(buf) => Ok(Some(buf)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    #[instrument(skip(self))]
+    fn new_merge(&mut self, after: Fd) -> Result<Fd, FsError> {
+        let position = self
+            .files
+            .iter()
+            .position(|fd| *fd == after)
+            .ok_or_else(|| ConcreteSystem::not_found(after))?;
+
+        let mut id = self.id(after)?;
+        id.merge += 1;
+        while self.ids.values().any(|existing| *existing == id) {
+            id.merge += 1;
+        }
+
+        let fd = self.next_fd();
+        self.ids.insert(fd, id);
+        self.merging.insert(fd);
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(self.data_path(fd)?)?;
+        trace!(fd = ?fd, id = ?id, "Created merge file");
+
+        self.map.insert(fd, file);
+        self.files.insert(position + 1, fd);
+
+        Ok(fd)
+    }
+
+    #[instrument(skip(self))]
+    fn commit_merge(&mut self, file: Fd) -> io::Result<()> {
+        let merge_path = self.data_path(file)?;
+        self.merging.remove(&file);
+        let path = self.data_path(file)?;
+
+        self.map
+            .get(&file)
+            .ok_or_else(|| ConcreteSystem::not_found(file))?
+            .sync_all()?;
+        fs::rename(merge_path, path)?;
+        self.sync_dir()
+    }
+
+    #[instrument(skip(self))]
+    fn remove(&mut self, file: Fd) -> io::Result<()> {
+        let path = self.data_path(file)?;
+        let hint_path = self.hint_path(file)?;
+
+        // Remove the data file first, a hint file without its data file is ignored on startup
+        fs::remove_file(path)?;
+        match fs::remove_file(hint_path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        self.sync_dir()?;
+
+        self.map.remove(&file);
+        self.ids.remove(&file);
+        self.merging.remove(&file);
+        self.files.retain(|fd| *fd != file);
+        Ok(())
+    }
+
+    /// Hard links the immutable files and their hint files into `dest`, and copies the active
+    /// file under the name it would get once rotated
+    #[instrument(skip(self, active_hints, existing))]
+    fn checkpoint(
+        &self,
+        dest: &Path,
+        active_len: u64,
+        active_hints: &[u8],
+        existing: &BTreeMap<String, u64>,
+    ) -> io::Result<BTreeMap<String, u64>> {
+        fs::create_dir_all(dest)?;
+        if fs::read_dir(dest)?.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Checkpoint directory {} is not empty", dest.display()),
+            ));
+        }
+
+        // The newest data file of an earlier copy was the active file back then. A crash may have
+        // dropped its unsynced writes since, and later writes can grow it back to the same size
+        // with different contents, so it is copied again along with its hint file.
+        let previous_active = existing
+            .keys()
+            .filter_map(|name| FileId::parse(name))
+            .max()
+            .map(|id| id.stem());
+        let was_active = |name: &str| {
+            previous_active.as_ref().is_some_and(|stem| {
+                name.strip_prefix(stem.as_str())
+                    .is_some_and(|ext| ext == ".db" || ext == ".hint")
+            })
+        };
+
+        let mut files = BTreeMap::new();
+        // Data files never change once they are immutable, so the same name and size means the
+        // same contents
+        let mut share = |from: &Path, name: String| -> io::Result<()> {
+            let size = fs::metadata(from)?.len();
+            if was_active(&name) || existing.get(&name) != Some(&size) {
+                trace!(name, "Linking file into checkpoint");
+                link_or_copy(from, &dest.join(&name))?;
+            }
+            files.insert(name, size);
+            Ok(())
+        };
+
+        for fd in self.files() {
+            if fd == self.active {
+                continue;
+            }
+
+            let stem = self.id(fd)?.stem();
+            share(&self.data_path(fd)?, format!("{stem}.db"))?;
+            match share(&self.hint_path(fd)?, format!("{stem}.hint")) {
+                Ok(()) => {}
+                // Missing hint files are recreated when the checkpoint is opened
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        // Seal the copy of the active file. Everything past `active_len` is still being written.
+        // The active file keeps changing, so it is copied even if an earlier copy has the size.
+        let stem = self.id(self.active)?.stem();
+        let name = format!("{stem}.db");
+        let active = self
+            .map
+            .get(&self.active)
+            .ok_or_else(|| ConcreteSystem::not_found(self.active))?;
+        let sealed = File::create(dest.join(&name))?;
+        let mut buf = vec![0; COPY_CHUNK];
+        let mut offset = 0;
+        while offset < active_len {
+            let len = (active_len - offset).min(COPY_CHUNK as u64) as usize;
+            active.read_exact_at(&mut buf[..len], offset)?;
+            sealed.write_all_at(&buf[..len], offset)?;
+            offset += len as u64;
+        }
+        sealed.sync_all()?;
+        files.insert(name, active_len);
+
+        let name = format!("{stem}.hint");
+        let hint_path = dest.join(&name);
+        fs::write(&hint_path, active_hints)?;
+        File::open(hint_path)?.sync_all()?;
+        files.insert(name, active_hints.len() as u64);
+
+        // Persist the directory entries of the new files
+        File::open(dest)?.sync_all()?;
+        info!(dest = ?dest, "Wrote checkpoint");
+        Ok(files)
     }
 
     fn active(&self) -> Fd {
         self.active
     }
+
+    fn files(&self) -> Vec<Fd> {
+        self.files
+            .iter()
+            .filter(|fd| !self.merging.contains(fd))
+            .copied()
+            .collect()
+    }
 }
 
-impl ClockSource for ConcreteSystem {}
+impl ClockSource for ConcreteSystem {
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
 
 impl System for ConcreteSystem {}
 
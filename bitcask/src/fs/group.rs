@@ -0,0 +1,124 @@
+//! Group commit for writes to the active file
+//!
+//! Writers queue their serialized records, either a single entry or a whole write batch. The
+//! first writer to find no write in progress becomes the leader: it takes every queued record,
+//! writes them to the active file in one go and reports the outcome back to the writers that
+//! queued them. Writers arriving while the leader is busy wait for it, and one of them leads the
+//! next group. Under concurrent load this amortizes the cost of the write syscall and the sync
+//! over many entries.
+use std::{
+    collections::HashMap,
+    io, mem,
+    sync::{Condvar, Mutex},
+};
+
+use tracing::trace;
+
+use crate::{repr::Header, CacheEntry};
+
+use super::FsError;
+
+/// Serialized entries which have to end up in the same data file, back to back
+#[derive(Debug)]
+pub(super) struct Record {
+    /// Header and plaintext key of every entry the KeyDir needs to know about, along with where
+    /// the entry starts in `buf`
+    pub entries: Vec<(Header, Vec<u8>, usize)>,
+    pub buf: Vec<u8>,
+}
+
+impl Record {
+    /// Every entry in the record, along with its key and offset into the record
+    pub fn entries(&self) -> impl Iterator<Item = (&Header, &[u8], usize)> {
+        self.entries
+            .iter()
+            .map(|(header, key, start)| (header, key.as_slice(), *start))
+    }
+}
+
+/// A record waiting to be written
+#[derive(Debug)]
+pub(super) struct Pending {
+    ticket: u64,
+    pub record: Record,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct CommitQueue {
+    state: Mutex<QueueState>,
+    /// Signalled whenever a leader has finished writing its group
+    done: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    pending: Vec<Pending>,
+    completed: HashMap<u64, Result<Vec<CacheEntry>, FsError>>,
+    next_ticket: u64,
+    /// Whether a leader is currently writing a group
+    writing: bool,
+}
+
+impl CommitQueue {
+    /// Queues a record and blocks until it has been written
+    ///
+    /// If this writer ends up leading a group, `write_group` is called with every record queued
+    /// so far, in queue order, and must return the locations of their entries in the same order.
+    pub fn commit<F>(&self, record: Record, write_group: F) -> Result<Vec<CacheEntry>, FsError>
+    where
+        F: FnOnce(&[Pending]) -> Result<Vec<Vec<CacheEntry>>, FsError>,
+    {
+        let mut state = self.state.lock().expect("Unable to lock commit queue");
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.pending.push(Pending { ticket, record });
+
+        let mut write_group = Some(write_group);
+        loop {
+            if let Some(result) = state.completed.remove(&ticket) {
+                return result;
+            }
+
+            // Every entry taken by a leader is completed before the next one takes over, so our
+            // entry is still queued and we get to write it ourselves.
+            if !state.writing {
+                let write_group = write_group.take().expect("Led more than one group");
+                state.writing = true;
+                let group = mem::take(&mut state.pending);
+                drop(state);
+
+                trace!(group_size = group.len(), "Leading group commit");
+                let result = write_group(&group);
+
+                state = self.state.lock().expect("Unable to lock commit queue");
+                match result {
+                    Ok(entries) => {
+                        for (pending, entries) in group.iter().zip(entries) {
+                            state.completed.insert(pending.ticket, Ok(entries));
+                        }
+                    }
+                    // The whole group shares the fate of the write
+                    Err(err) => {
+                        for pending in &group {
+                            state.completed.insert(pending.ticket, Err(err.duplicate()));
+                        }
+                    }
+                }
+                state.writing = false;
+                self.done.notify_all();
+                continue;
+            }
+
+            state = self.done.wait(state).expect("Unable to lock commit queue");
+        }
+    }
+}
+
+impl FsError {
+    /// Copies the error, for reporting a single failure to every writer in a group
+    fn duplicate(&self) -> FsError {
+        match self {
+            FsError::Io { source, .. } => io::Error::new(source.kind(), source.to_string()).into(),
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Memory mapped reads of immutable data files
+//!
+//! [`MmapSystem`] lays out files exactly like [`ConcreteSystem`], which it builds on, and only
+//! changes how they are read. Immutable files never change once written, so they are mapped into
+//! memory when they become immutable and reads from them turn into plain copies out of the map.
+//! The active file is still written and read through positioned I/O.
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, IoSlice},
+    ops::{Deref, Range},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+
+use memmap2::Mmap;
+use tracing::{instrument, trace};
+
+use crate::{ClockSource, FileSystem, System};
+
+use super::{ConcreteSystem, Fd, FsError};
+
+/// Implements the FileSystem interface for an actual system, reading immutable files through
+/// memory maps
+///
+/// Data files written by a [`ConcreteSystem`] can be opened with this file system and vice versa.
+pub struct MmapSystem {
+    files: ConcreteSystem,
+    /// Maps of the immutable files. Merged files are mapped once they are committed.
+    maps: HashMap<Fd, Arc<Mmap>>,
+}
+
+impl MmapSystem {
+    /// Wraps `files`, mapping every immutable file it has opened
+    fn map_immutable(files: ConcreteSystem) -> Result<Self, FsError> {
+        let mut system = MmapSystem {
+            files,
+            maps: HashMap::new(),
+        };
+
+        let active = system.files.active();
+        for file in system.files.files() {
+            if file != active {
+                system.map(file)?;
+            }
+        }
+
+        Ok(system)
+    }
+
+    /// Maps the given immutable file into memory
+    fn map(&mut self, file: Fd) -> io::Result<()> {
+        // SAFETY: Immutable files are never written to or truncated. Removing them only unlinks
+        // them, which leaves existing maps intact. Anything else modifying the files of the data
+        // store while it is open is not supported.
+        let map = unsafe { Mmap::map(self.files.file(file)?)? };
+        trace!(file = ?file, len = map.len(), "Mapped immutable file");
+        self.maps.insert(file, Arc::new(map));
+        Ok(())
+    }
+}
+
+/// Bytes of a memory mapped data file, borrowed without copying them
+///
+/// The slice keeps the map alive, so it stays valid even after a merge deletes the file.
+#[derive(Debug, Clone)]
+pub struct MappedSlice {
+    map: Arc<Mmap>,
+    range: Range<usize>,
+}
+
+impl MappedSlice {
+    /// Narrows the slice down to `range`, relative to its start
+    pub(crate) fn slice(self, range: Range<usize>) -> Self {
+        assert!(range.start <= range.end && range.end <= self.range.len());
+        MappedSlice {
+            map: self.map,
+            range: self.range.start + range.start..self.range.start + range.end,
+        }
+    }
+}
+
+impl Deref for MappedSlice {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map[self.range.clone()]
+    }
+}
+
+impl AsRef<[u8]> for MappedSlice {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl FileSystem for MmapSystem {
+    fn init(path: impl Into<PathBuf>) -> Result<Self, FsError> {
+        MmapSystem::map_immutable(ConcreteSystem::init(path)?)
+    }
+
+    fn init_read_only(path: impl Into<PathBuf>) -> Result<Self, FsError> {
+        MmapSystem::map_immutable(ConcreteSystem::init_read_only(path)?)
+    }
+
+    fn new_active(&mut self) -> Result<Fd, FsError> {
+        let sealed = self.files.active();
+        let active = self.files.new_active()?;
+        self.map(sealed)?;
+        Ok(active)
+    }
+
+    fn write_at(&self, file: Fd, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.files.write_at(file, buf, offset)
+    }
+
+    fn write_vectored_at(&self, file: Fd, bufs: &[IoSlice<'_>], offset: u64) -> io::Result<usize> {
+        self.files.write_vectored_at(file, bufs, offset)
+    }
+
+    #[instrument(skip(self, buf))]
+    fn read_exact_at(&self, file: Fd, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let Some(map) = self.maps.get(&file) else {
+            return self.files.read_exact_at(file, buf, offset);
+        };
+
+        let chunk = usize::try_from(offset)
+            .ok()
+            .and_then(|start| map.get(start..start.checked_add(buf.len())?))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("Read past the end of {file}"),
+                )
+            })?;
+        trace!(file = ?file, read_size = buf.len(), "Copying buf out of mapped file");
+        buf.copy_from_slice(chunk);
+        Ok(())
+    }
+
+    fn read_mapped(&self, file: Fd, offset: u64, len: usize) -> Option<MappedSlice> {
+        let map = self.maps.get(&file)?;
+        let start = usize::try_from(offset).ok()?;
+        let range = start..start.checked_add(len)?;
+        if range.end > map.len() {
+            return None;
+        }
+
+        Some(MappedSlice {
+            map: Arc::clone(map),
+            range,
+        })
+    }
+
+    fn file_size(&self, file: Fd) -> io::Result<u64> {
+        match self.maps.get(&file) {
+            Some(map) => Ok(map.len() as u64),
+            None => self.files.file_size(file),
+        }
+    }
+
+    fn flush(&mut self, file: Fd) -> io::Result<()> {
+        self.files.flush(file)
+    }
+
+    fn sync(&mut self, file: Fd) -> io::Result<()> {
+        self.files.sync(file)
+    }
+
+    fn truncate(&mut self, file: Fd, len: u64) -> io::Result<()> {
+        // Accessing a map past the end of its file is fatal, never keep one around
+        self.maps.remove(&file);
+        self.files.truncate(file, len)
+    }
+
+    fn write_hint(&mut self, file: Fd, buf: &[u8]) -> io::Result<()> {
+        self.files.write_hint(file, buf)
+    }
+
+    fn read_hint(&self, file: Fd) -> io::Result<Option<Vec<u8>>> {
+        self.files.read_hint(file)
+    }
+
+    fn new_merge(&mut self, after: Fd) -> Result<Fd, FsError> {
+        self.files.new_merge(after)
+    }
+
+    fn commit_merge(&mut self, file: Fd) -> io::Result<()> {
+        self.files.commit_merge(file)?;
+        self.map(file)
+    }
+
+    fn remove(&mut self, file: Fd) -> io::Result<()> {
+        self.maps.remove(&file);
+        self.files.remove(file)
+    }
+
+    fn checkpoint(
+        &self,
+        dest: &Path,
+        active_len: u64,
+        active_hints: &[u8],
+        existing: &BTreeMap<String, u64>,
+    ) -> io::Result<BTreeMap<String, u64>> {
+        self.files
+            .checkpoint(dest, active_len, active_hints, existing)
+    }
+
+    fn active(&self) -> Fd {
+        self.files.active()
+    }
+
+    fn files(&self) -> Vec<Fd> {
+        self.files.files()
+    }
+}
+
+impl ClockSource for MmapSystem {
+    fn system_time(&self) -> SystemTime {
+        self.files.system_time()
+    }
+
+    fn instant(&self) -> Instant {
+        self.files.instant()
+    }
+}
+
+impl System for MmapSystem {}
@@ -1,11 +1,28 @@
 mod concrete;
+mod group;
+mod mmap;
 
 pub use concrete::ConcreteSystem;
-use std::{backtrace::Backtrace, fmt, io, path::PathBuf, sync::RwLock};
+use group::{CommitQueue, Pending, Record};
+pub use mmap::{MappedSlice, MmapSystem};
+use std::{
+    backtrace::Backtrace,
+    collections::BTreeMap,
+    fmt,
+    io::{self, IoSlice},
+    mem,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::{Instant, SystemTime},
+};
 
 use tracing::{debug, info, instrument, trace};
 
-use super::{repr::Entry, CacheEntry};
+use super::{
+    encryption::{Encryption, EncryptionError},
+    repr::{Entry, FileHeader, HintHeader},
+    CacheEntry, ClockSource, SyncPolicy,
+};
 
 /// An offset of an entry in a data file
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
@@ -15,6 +32,9 @@ pub struct Offset(pub usize);
 #[derive(Debug)]
 pub(crate) struct Fs<T> {
     inner: RwLock<FsInner<T>>,
+    queue: CommitQueue,
+    /// Seals entries and hint files as they are written, if set
+    encryption: Option<Encryption>,
 }
 
 #[derive(Debug)]
@@ -22,77 +42,158 @@ struct FsInner<T> {
     fs_impl: T,
     cursor: u64,
     active_fd: Fd,
+    /// Size in bytes after which the active file is rotated into an immutable one
+    active_threshold: u64,
+    /// Hint entries for everything written to the active file so far, persisted as the hint file
+    /// of the active file once it is rotated
+    hints: Vec<u8>,
+    sync_policy: SyncPolicy,
+    /// Writes to the active file since it was last synced
+    unsynced: usize,
+    last_sync: Instant,
 }
 
 impl<T> Fs<T>
 where
-    T: FileSystem,
+    T: FileSystem + ClockSource,
 {
-    pub fn new(fs: T) -> Result<Self, FsError> {
+    pub fn new(
+        fs: T,
+        active_threshold: usize,
+        sync_policy: SyncPolicy,
+        encryption: Option<Encryption>,
+    ) -> Result<Self, FsError> {
         let active = fs.active();
+        let last_sync = fs.instant();
         Ok(Fs {
             inner: RwLock::new(FsInner {
                 fs_impl: fs,
                 cursor: 0,
                 active_fd: active,
+                active_threshold: active_threshold as u64,
+                hints: Vec::new(),
+                sync_policy,
+                unsynced: 0,
+                last_sync,
             }),
+            queue: CommitQueue::default(),
+            encryption,
         })
     }
 
+    /// Appends an entry to the active file
+    ///
+    /// Concurrent writers are grouped together, see [`CommitQueue`].
     #[instrument(skip(self, entry), fields(entry.header))]
     pub fn write_entry<'entry>(&self, entry: Entry<'entry>) -> Result<CacheEntry, FsError> {
         info!(
             entry_size = entry.len(),
             "Inserting entry into current active file"
         );
-        let buf = entry.serialize();
+        let (header, buf) = entry.serialize(self.encryption.as_ref());
+        let record = Record {
+            entries: vec![(header, entry.key().to_vec(), 0)],
+            buf,
+        };
+
+        let mut entries = self.queue.commit(record, |group| self.write_group(group))?;
+        Ok(entries.remove(0))
+    }
 
-        let mut size = 0;
+    /// Appends the entries of a write batch to the active file, followed by `commit`, the
+    /// serialized record that commits them
+    ///
+    /// The batch is written in one go and never spans two data files, a crash can only leave it
+    /// without its commit record at the very end of the active file.
+    #[instrument(skip_all, fields(batch_size = batch.len()))]
+    pub fn write_batch(
+        &self,
+        batch: Vec<Entry<'_>>,
+        commit: &[u8],
+    ) -> Result<Vec<CacheEntry>, FsError> {
+        let mut record = Record {
+            entries: Vec::with_capacity(batch.len()),
+            buf: Vec::new(),
+        };
+        for entry in batch {
+            let entry = entry.batched();
+            let (header, buf) = entry.serialize(self.encryption.as_ref());
+            record
+                .entries
+                .push((header, entry.key().to_vec(), record.buf.len()));
+            record.buf.extend_from_slice(&buf);
+        }
+        record.buf.extend_from_slice(commit);
+
+        self.queue.commit(record, |group| self.write_group(group))
+    }
 
+    /// Writes a group of entries to the active file, rotating it as needed
+    #[instrument(skip_all, fields(group_size = group.len()))]
+    fn write_group(&self, group: &[Pending]) -> Result<Vec<Vec<CacheEntry>>, FsError> {
         // Get write lock on inner struct to linearize writes to the WAL in the active db file.
         let mut inner = self.inner.write().expect("Unable to lock active file");
-        let current_active = inner.active_fd;
 
-        debug!(pos = inner.cursor);
+        let mut entries = Vec::with_capacity(group.len());
+        let mut rest = group;
+        while !rest.is_empty() {
+            // Only the last entry written to a file may push it past the threshold, so that
+            // grouping writes does not change where files get rotated.
+            let mut cursor = inner.cursor;
+            let mut len = 0;
+            while len < rest.len() && (len == 0 || cursor < inner.active_threshold) {
+                cursor += rest[len].record.buf.len() as u64;
+                len += 1;
+            }
+
+            let (chunk, remaining) = rest.split_at(len);
+            entries.extend(inner.write_chunk(chunk)?);
+            rest = remaining;
+
+            // Rotate while still holding the lock, so that concurrent writers can't push the
+            // active file past the threshold between our write and the swap.
+            if inner.cursor >= inner.active_threshold {
+                inner.swap_active(self.encryption.as_ref())?;
+            }
+        }
 
-        while size < buf.len() {
-            size += inner.fs_impl.write_at(current_active, &buf, inner.cursor)?;
+        if inner.unsynced > 0 && inner.should_sync() {
+            inner.sync()?;
         }
 
-        // Flush to ensure write is persisted
-        inner.fs_impl.flush(current_active)?;
-
-        let current = Offset(inner.cursor as usize);
-        // Update our cursor into the active file
-        inner.cursor += size as u64;
-        Ok(CacheEntry {
-            fd: inner.fs_impl.active(),
-            value_size: entry.header.value_size,
-            offset: current,
-            timestamp: entry.header.timestamp,
-        })
+        Ok(entries)
     }
 
-    #[instrument(skip(self, buf), fields(read_size=buf.len()))]
-    /// Reads a chunk the size of the given buffer into the active file at the provided offset
-    pub fn get_chunk(&self, offset: Offset, buf: &mut [u8]) -> Result<(), FsError> {
-        info!("Reading chunk from active file");
-        let inner = self
-            .inner
-            .read()
-            .expect("Unable to obtain read lock on active file");
+    /// Durably persists every write made to the active file so far
+    #[instrument(skip(self))]
+    pub fn sync(&self) -> Result<(), FsError> {
+        let mut inner = self.inner.write().expect("Unable to lock active file");
+        inner.sync()
+    }
 
-        inner
-            .fs_impl
-            .read_exact_at(inner.active_fd, buf, offset.0 as u64)?;
+    /// Syncs the active file if the sync policy asks for it, which for an interval depends on
+    /// the time rather than on the next write
+    pub fn sync_due(&self) -> Result<(), FsError> {
+        let mut inner = self.inner.write().expect("Unable to lock active file");
+        if inner.unsynced > 0 && inner.should_sync() {
+            inner.sync()?;
+        }
+        Ok(())
+    }
 
+    /// Syncs the writes the sync policy has left unsynced so far, unless it never syncs
+    pub fn sync_pending(&self) -> Result<(), FsError> {
+        let mut inner = self.inner.write().expect("Unable to lock active file");
+        if inner.sync_policy != SyncPolicy::Never && inner.unsynced > 0 {
+            inner.sync()?;
+        }
         Ok(())
     }
 
     /// Get a chunk of buf.len() from file associated with given Fd
-    #[instrument(skip(self, buf))]
+    #[instrument(skip(self, buf), fields(read_size=buf.len()))]
     pub fn get_chunk_fd(&self, offset: Offset, buf: &mut [u8], fd: Fd) -> Result<(), FsError> {
-        info!(fd = ?fd, "reading chunk from immutable file");
+        info!(fd = ?fd, "reading chunk from file");
 
         let inner = self
             .inner
@@ -104,29 +205,280 @@ where
         Ok(())
     }
 
-    pub fn active_size(&self) -> Result<u64, FsError> {
+    /// Borrows `len` bytes at `offset` of the file associated with the given Fd, if the file
+    /// system supports it, see [`FileSystem::read_mapped`]
+    pub fn get_mapped(&self, offset: Offset, len: usize, fd: Fd) -> Option<MappedSlice> {
+        let inner = self.inner.read().expect("Unable to lock active file");
+        inner.fs_impl.read_mapped(fd, offset.0 as u64, len)
+    }
+
+    /// Size of the file associated with the given Fd as reported by the file system
+    pub fn file_size(&self, fd: Fd) -> Result<u64, FsError> {
+        let inner = self.inner.read().expect("Unable to lock active file");
+        Ok(inner.fs_impl.file_size(fd)?)
+    }
+
+    /// Reads the hint file associated with the data file of the given Fd, if there is one
+    ///
+    /// Hint files which can't be opened with the configured keys are returned as they are. They
+    /// are either in plaintext or fail to parse, which falls back to replaying the data file.
+    #[instrument(skip(self))]
+    pub fn read_hint(&self, fd: Fd) -> Result<Option<Vec<u8>>, FsError> {
+        let inner = self.inner.read().expect("Unable to lock active file");
+        let hints = inner.fs_impl.read_hint(fd)?;
+        Ok(hints.map(|hints| match &self.encryption {
+            Some(encryption) => encryption.open(&hints).unwrap_or(hints),
+            None => hints,
+        }))
+    }
+
+    /// Persists the hint file for the data file of the given Fd
+    #[instrument(skip(self, hints), fields(hint_size = hints.len()))]
+    pub fn write_hint(&self, fd: Fd, hints: &[u8]) -> Result<(), FsError> {
+        let mut inner = self.inner.write().expect("Unable to lock active file");
+        inner.write_hint(fd, hints, self.encryption.as_ref())
+    }
+
+    /// Opens the sealed key and value of an encrypted entry
+    pub fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        match &self.encryption {
+            Some(encryption) => encryption.open(sealed),
+            None => Err(EncryptionError::NotConfigured),
+        }
+    }
+
+    pub fn encryption(&self) -> Option<&Encryption> {
+        self.encryption.as_ref()
+    }
+
+    /// Writes a copy of every data file written so far into `dest`, skipping the files listed in
+    /// `existing`
+    ///
+    /// Writes are blocked while the copy is made, which keeps the active file from growing or
+    /// being rotated in the meantime. Returns every file the copy consists of, see
+    /// [`FileSystem::checkpoint`].
+    #[instrument(skip(self, existing))]
+    pub fn checkpoint(
+        &self,
+        dest: &Path,
+        existing: &BTreeMap<String, u64>,
+    ) -> Result<BTreeMap<String, u64>, FsError> {
         let inner = self.inner.read().expect("Unable to lock active file");
-        Ok(inner.cursor)
+        info!(active_len = inner.cursor, "Writing checkpoint");
+        let hints = match &self.encryption {
+            Some(encryption) => encryption.seal(&[&inner.hints]),
+            None => inner.hints.clone(),
+        };
+        let files = inner
+            .fs_impl
+            .checkpoint(dest, inner.cursor, &hints, existing)?;
+        Ok(files)
+    }
+
+    /// Replaces the hints tracked for the active file
+    ///
+    /// Used on startup, after the existing entries of the active file have been replayed.
+    pub fn restore_hints(&self, hints: Vec<u8>) {
+        let mut inner = self.inner.write().expect("Unable to lock active file");
+        inner.hints = hints;
+    }
+
+    /// Writes all of `buf` into the file associated with the given Fd
+    ///
+    /// This bypasses the active file cursor and is meant for files that are being written outside
+    /// of the regular write path, like the output of a merge.
+    #[instrument(skip(self, buf), fields(write_size = buf.len()))]
+    pub fn write_all_at(&self, fd: Fd, buf: &[u8], offset: Offset) -> Result<(), FsError> {
+        let inner = self.inner.write().expect("Unable to lock active file");
+        inner.write_all_at(fd, buf, offset.0 as u64)
+    }
+
+    /// Creates the output file of a merge, ordered right after `after`
+    ///
+    /// Entries go right after the file header, at [`FileHeader::LEN`].
+    #[instrument(skip(self))]
+    pub fn new_merge(&self, after: Fd) -> Result<Fd, FsError> {
+        let mut inner = self.inner.write().expect("Unable to lock active file");
+        let fd = inner.fs_impl.new_merge(after)?;
+        inner.write_all_at(fd, FileHeader::current().serialize(), 0)?;
+        Ok(fd)
+    }
+
+    /// Makes the output of a merge durable and visible to future instances
+    #[instrument(skip(self))]
+    pub fn commit_merge(&self, fd: Fd) -> Result<(), FsError> {
+        let mut inner = self.inner.write().expect("Unable to lock active file");
+        inner.fs_impl.commit_merge(fd)?;
+        Ok(())
+    }
+
+    /// Deletes a data file and its hint file
+    #[instrument(skip(self))]
+    pub fn remove(&self, fd: Fd) -> Result<(), FsError> {
+        let mut inner = self.inner.write().expect("Unable to lock active file");
+        inner.fs_impl.remove(fd)?;
+        Ok(())
+    }
+
+    /// Truncates the file associated with the given Fd to `len` bytes
+    #[instrument(skip(self))]
+    pub fn truncate(&self, fd: Fd, len: u64) -> Result<(), FsError> {
+        let mut inner = self.inner.write().expect("Unable to lock active file");
+        inner.fs_impl.truncate(fd, len)?;
+        Ok(())
+    }
+
+    /// All data files, ordered from oldest to newest
+    pub fn files(&self) -> Vec<Fd> {
+        let inner = self.inner.read().expect("Unable to lock active file");
+        inner.fs_impl.files()
     }
 
     pub fn active_fd(&self) -> Fd {
         let inner = self.inner.read().expect("Unable to lock active file");
         inner.active_fd
     }
+}
 
-    #[instrument(skip(self))]
-    pub fn swap_active(&self) -> Result<(), FsError> {
-        let mut inner = self.inner.write().unwrap();
-        let new_active = inner.fs_impl.new_active()?;
+impl<T> FsInner<T>
+where
+    T: FileSystem + ClockSource,
+{
+    fn write_all_at(&self, fd: Fd, buf: &[u8], offset: u64) -> Result<(), FsError> {
+        let mut size = 0;
+        while size < buf.len() {
+            size += self
+                .fs_impl
+                .write_at(fd, &buf[size..], offset + size as u64)?;
+        }
+        Ok(())
+    }
+
+    fn should_sync(&self) -> bool {
+        match self.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryN(n) => self.unsynced >= n.max(1),
+            SyncPolicy::Interval(interval) => {
+                self.fs_impl.instant().duration_since(self.last_sync) >= interval
+            }
+            SyncPolicy::Never => false,
+        }
+    }
+
+    /// Writes `chunk` right after the last entry in the active file, using a single vectored
+    /// write where the file system allows
+    fn write_chunk(&mut self, chunk: &[Pending]) -> Result<Vec<Vec<CacheEntry>>, FsError> {
+        let current_active = self.active_fd;
+        debug!(pos = self.cursor, entries = chunk.len());
+
+        let mut slices: Vec<IoSlice<'_>> = chunk
+            .iter()
+            .map(|pending| IoSlice::new(&pending.record.buf))
+            .collect();
+        let mut slices = &mut slices[..];
+        let mut size = 0;
+        while !slices.is_empty() {
+            let written = self.fs_impl.write_vectored_at(
+                current_active,
+                slices,
+                self.cursor + size as u64,
+            )?;
+            if written == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+            size += written;
+            IoSlice::advance_slices(&mut slices, written);
+        }
+
+        // Hand the write over to the OS, whether it also gets synced is up to the sync policy
+        self.fs_impl.flush(current_active)?;
+        self.unsynced += chunk.len();
+
+        let mut written = Vec::with_capacity(chunk.len());
+        for pending in chunk {
+            let mut entries = Vec::with_capacity(pending.record.entries.len());
+            for (header, key, start) in pending.record.entries() {
+                let current = Offset(self.cursor as usize + start);
+                HintHeader::append(&mut self.hints, header, key, current.0 as u64);
+
+                entries.push(CacheEntry {
+                    fd: current_active,
+                    value_size: header.value_size,
+                    offset: current,
+                    expires_at: header.expires_at,
+                });
+            }
+
+            // Update our cursor into the active file
+            self.cursor += pending.record.buf.len() as u64;
+            written.push(entries);
+        }
+
+        Ok(written)
+    }
+
+    /// Persists `hints` as the hint file of `fd`, sealed as a whole if `encryption` is given
+    fn write_hint(
+        &mut self,
+        fd: Fd,
+        hints: &[u8],
+        encryption: Option<&Encryption>,
+    ) -> Result<(), FsError> {
+        match encryption {
+            Some(encryption) => self.fs_impl.write_hint(fd, &encryption.seal(&[hints]))?,
+            None => self.fs_impl.write_hint(fd, hints)?,
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        trace!(unsynced = self.unsynced, "Syncing active file");
+        self.fs_impl.sync(self.active_fd)?;
+        self.unsynced = 0;
+        self.last_sync = self.fs_impl.instant();
+        Ok(())
+    }
+
+    #[instrument(skip(self, encryption))]
+    fn swap_active(&mut self, encryption: Option<&Encryption>) -> Result<(), FsError> {
+        // Immutable files are never written to again, so this is the last chance to sync them
+        if self.sync_policy != SyncPolicy::Never && self.unsynced > 0 {
+            self.sync()?;
+        }
+
+        let old_active = self.active_fd;
+        let new_active = self.fs_impl.new_active()?;
         trace!(new_active = ?new_active, "Swapping active file");
 
-        // Update the active Fd and make sure to reset the cursor into the new file
-        inner.active_fd = new_active;
-        inner.cursor = 0;
+        // The old active file is now immutable, persist its hints next to it
+        let hints = mem::take(&mut self.hints);
+        self.write_hint(old_active, &hints, encryption)?;
+
+        // Update the active Fd and make sure to reset the cursor into the new file, right after
+        // its file header
+        let header = FileHeader::current();
+        self.write_all_at(new_active, header.serialize(), 0)?;
+        self.active_fd = new_active;
+        self.cursor = FileHeader::LEN;
         Ok(())
     }
 }
 
+impl<T> ClockSource for Fs<T>
+where
+    T: ClockSource,
+{
+    fn system_time(&self) -> SystemTime {
+        let inner = self.inner.read().expect("Unable to lock active file");
+        inner.fs_impl.system_time()
+    }
+
+    fn instant(&self) -> Instant {
+        let inner = self.inner.read().expect("Unable to lock active file");
+        inner.fs_impl.instant()
+    }
+}
+
 impl<T> Fs<T> {
     pub fn update_cursor(&self, loc: u64) {
         let mut inner = self.inner.write().expect("Unable to obtain write lock");
@@ -157,7 +509,16 @@ impl Fd {
     }
 
     pub fn increment(&mut self) {
-        self.0 = self.0 + 1;
+        self.0 += 1;
+    }
+
+    /// Position of the Fd in the sequence of Fds handed out, for packing it into less space
+    pub(crate) fn index(self) -> usize {
+        self.0
+    }
+
+    pub(crate) fn from_index(index: usize) -> Self {
+        Fd(index)
     }
 }
 
@@ -172,14 +533,104 @@ impl fmt::Display for Fd {
 /// Trait implementations do not need to be threadsafe.
 pub trait FileSystem {
     fn write_at(&self, file: Fd, buf: &[u8], offset: u64) -> io::Result<usize>;
+
+    /// Writes the buffers back to back starting at `offset`, returning the number of bytes
+    /// written
+    ///
+    /// The default implementation copies the buffers into one and issues a single `write_at`.
+    fn write_vectored_at(&self, file: Fd, bufs: &[IoSlice<'_>], offset: u64) -> io::Result<usize> {
+        let buf: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+        self.write_at(file, &buf, offset)
+    }
     fn read_exact_at(&self, file: Fd, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Borrows `len` bytes at `offset` of an immutable file without copying them
+    ///
+    /// The default implementation does not support this, callers fall back to `read_exact_at`.
+    fn read_mapped(&self, file: Fd, offset: u64, len: usize) -> Option<MappedSlice> {
+        let _ = (file, offset, len);
+        None
+    }
     fn file_size(&self, file: Fd) -> io::Result<u64>;
     fn flush(&mut self, file: Fd) -> io::Result<()>;
+
+    /// Durably persists the contents of the given file, its metadata aside from the size does not
+    /// need to be persisted
+    fn sync(&mut self, file: Fd) -> io::Result<()>;
+    fn truncate(&mut self, file: Fd, len: u64) -> io::Result<()>;
+
+    /// Atomically writes `buf` as the hint file of the given data file, replacing any existing
+    /// hint file
+    fn write_hint(&mut self, file: Fd, buf: &[u8]) -> io::Result<()>;
+
+    /// Reads the whole hint file of the given data file, if one exists
+    fn read_hint(&self, file: Fd) -> io::Result<Option<Vec<u8>>>;
+
+    /// Creates an empty data file for the output of a merge
+    ///
+    /// The new file is ordered right after `after`, the newest file that is part of the merge. It
+    /// must not be picked up by `init` until it is committed through `commit_merge`.
+    fn new_merge(&mut self, after: Fd) -> Result<Fd, FsError>;
+
+    /// Durably persists a merged file and makes it part of the data files found by `init`
+    fn commit_merge(&mut self, file: Fd) -> io::Result<()>;
+
+    /// Deletes a data file along with its hint file
+    fn remove(&mut self, file: Fd) -> io::Result<()>;
+
+    /// Writes a copy of every data file into the directory `dest`, which a new instance can be
+    /// initialized from
+    ///
+    /// Only the first `active_len` bytes of the active file are copied, and the copy is sealed
+    /// into an immutable file with `active_hints` as its hint file. Immutable files never change,
+    /// so implementations are free to share them with the copy instead of copying them.
+    ///
+    /// Files are identified by their name and size. Files listed in `existing` are already
+    /// present elsewhere, such as an earlier backup, and are left out. The exception are the active
+    /// file and whichever file was the active file when `existing` was written, which may have
+    /// changed without changing their size, so they are always copied. Returns the name and size
+    /// of every file making up the copy, including the ones left out.
+    ///
+    /// The default implementation does not support checkpoints.
+    fn checkpoint(
+        &self,
+        dest: &Path,
+        active_len: u64,
+        active_hints: &[u8],
+        existing: &BTreeMap<String, u64>,
+    ) -> io::Result<BTreeMap<String, u64>> {
+        let _ = (dest, active_len, active_hints, existing);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Checkpoints are not supported by this file system",
+        ))
+    }
     fn active(&self) -> Fd;
 
+    /// Every data file known to the file system, ordered from oldest to newest.
+    ///
+    /// The active file is always the last element. Rebuilding the KeyDir replays the files in this
+    /// order, so newer entries for a key must live in files that come later.
+    fn files(&self) -> Vec<Fd>;
+
     /// Creates a new instace of this FileSystemImpl
+    ///
+    /// Data files left behind by a previous instance at the same path must be reopened and made
+    /// addressable.
     fn init(path: impl Into<PathBuf>) -> Result<Self, FsError>
     where
         Self: Sized;
+
+    /// Opens the data files at `path` without creating, changing or removing any file
+    ///
+    /// The instance is only ever read from. If there is no active file, it is left out of
+    /// `files`. The default implementation is `init`, for file systems which do not persist
+    /// anything.
+    fn init_read_only(path: impl Into<PathBuf>) -> Result<Self, FsError>
+    where
+        Self: Sized,
+    {
+        Self::init(path)
+    }
     fn new_active(&mut self) -> Result<Fd, FsError>;
 }
@@ -0,0 +1,247 @@
+//! Iteration over the contents of a [`Cask`]
+//!
+//! Iterators copy the keys out of the KeyDir when they are created and release the KeyDir lock
+//! right away, so that they never block writers. Values are read from the data files one at a
+//! time as the iterator advances.
+use std::{
+    ops::{Bound, RangeBounds},
+    vec,
+};
+
+use crate::{keydir::prefix_end, Cask, CaskError, Snapshot, System};
+
+/// Iterator over the keys of a [`Cask`], created by [`Cask::keys`]
+pub struct Keys {
+    keys: vec::IntoIter<Vec<u8>>,
+}
+
+impl Keys {
+    pub(crate) fn new(keys: Vec<Vec<u8>>) -> Self {
+        Keys {
+            keys: keys.into_iter(),
+        }
+    }
+}
+
+impl Iterator for Keys {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.keys.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.keys.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Keys {}
+
+/// Iterator over the entries of a [`Cask`] or a [`Snapshot`], created by [`Cask::iter`] and
+/// friends
+pub struct Iter<'cask, T>
+where
+    T: System,
+{
+    source: Source<'cask, T>,
+    keys: Keys,
+}
+
+/// Where an [`Iter`] looks up the values of its keys
+pub(crate) enum Source<'cask, T>
+where
+    T: System,
+{
+    Cask(&'cask Cask<T>),
+    Snapshot(&'cask Snapshot<T>),
+}
+
+impl<'cask, T> Iter<'cask, T>
+where
+    T: System,
+{
+    pub(crate) fn new(source: Source<'cask, T>, keys: Keys) -> Self {
+        Iter { source, keys }
+    }
+}
+
+impl<'cask, T> Iterator for Iter<'cask, T>
+where
+    T: System,
+{
+    type Item = Result<(Vec<u8>, Vec<u8>), CaskError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for key in self.keys.by_ref() {
+            let value = match self.source {
+                Source::Cask(cask) => {
+                    let keydir = cask.inner.keydir.read().unwrap();
+                    // Keys removed or expired since the iterator was created are skipped
+                    let Some(cache_entry) = keydir.get_live(&key, cask.now()) else {
+                        continue;
+                    };
+                    cask.read_value(&cache_entry)
+                }
+                // Snapshots never change, every key is still there
+                Source::Snapshot(snapshot) => snapshot.get(&key),
+            };
+
+            return Some(value.map(|value| (key, value)));
+        }
+
+        None
+    }
+}
+
+/// Converts the bounds of a range of keys into owned bounds
+pub(crate) fn range_bounds<K, R>(range: R) -> (Bound<Vec<u8>>, Bound<Vec<u8>>)
+where
+    K: AsRef<[u8]>,
+    R: RangeBounds<K>,
+{
+    let to_owned = |bound: Bound<&K>| bound.map(|key| key.as_ref().to_vec());
+    (to_owned(range.start_bound()), to_owned(range.end_bound()))
+}
+
+/// Bounds of the range of keys starting with `prefix`
+pub(crate) fn prefix_bounds(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let start = Bound::Included(prefix.to_vec());
+    let end = match prefix_end(prefix) {
+        Some(end) => Bound::Excluded(end),
+        None => Bound::Unbounded,
+    };
+    (start, end)
+}
+
+impl<T> Cask<T>
+where
+    T: System,
+{
+    /// Every key in the data store, in no particular order
+    ///
+    /// The keys are copied when this is called, keys inserted afterwards are not part of the
+    /// iteration.
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{Cask, test::TestFileSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    ///     let cask: Cask<TestFileSystem> = Cask::new("")?;
+    ///     cask.insert("hello", "world")?;
+    ///     let keys: Vec<_> = cask.keys().collect();
+    ///     assert_eq!(keys, vec![b"hello".to_vec()]);
+    ///     # Ok(())
+    /// # }
+    /// ```
+    pub fn keys(&self) -> Keys {
+        Keys::new(self.inner.keydir.read().unwrap().keys(self.now()))
+    }
+
+    /// Every key within `range` along with its value, in ascending key order
+    ///
+    /// Keys are compared byte by byte. This is cheap with [`KeyDirKind::Ordered`], otherwise
+    /// every key in the data store is looked at. Values are read lazily just like with
+    /// [`Cask::iter`].
+    ///
+    /// [`KeyDirKind::Ordered`]: crate::KeyDirKind::Ordered
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{Cask, test::TestFileSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    ///     let cask: Cask<TestFileSystem> = Cask::new("")?;
+    ///     for key in ["a", "b", "c", "d"] {
+    ///         cask.insert(key, "value")?;
+    ///     }
+    ///     let keys = cask.range("b".."d").map(|entry| entry.map(|(key, _)| key));
+    ///     assert_eq!(keys.collect::<Result<Vec<_>, _>>()?, vec![b"b".to_vec(), b"c".to_vec()]);
+    ///     # Ok(())
+    /// # }
+    /// ```
+    pub fn range<K, R>(&self, range: R) -> Iter<'_, T>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let (start, end) = range_bounds(range);
+        let keys = self
+            .inner
+            .keydir
+            .read()
+            .unwrap()
+            .range(start, end, self.now());
+        Iter::new(Source::Cask(self), Keys::new(keys))
+    }
+
+    /// Every key starting with `prefix` along with its value, in ascending key order
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{Cask, Config, KeyDirKind, test::TestFileSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    ///     let config = Config { keydir: KeyDirKind::Ordered, ..Config::default() };
+    ///     let cask: Cask<TestFileSystem> = Cask::new_with_config("", config)?;
+    ///     cask.insert("user/2/name", "bob")?;
+    ///     cask.insert("user/1/name", "alice")?;
+    ///     cask.insert("group/1/name", "admins")?;
+    ///
+    ///     let names = cask.scan_prefix("user/").map(|entry| entry.map(|(_, value)| value));
+    ///     assert_eq!(names.collect::<Result<Vec<_>, _>>()?, vec![b"alice".to_vec(), b"bob".to_vec()]);
+    ///     # Ok(())
+    /// # }
+    /// ```
+    pub fn scan_prefix<P>(&self, prefix: P) -> Iter<'_, T>
+    where
+        P: AsRef<[u8]>,
+    {
+        let (start, end) = prefix_bounds(prefix.as_ref());
+        let keys = self
+            .inner
+            .keydir
+            .read()
+            .unwrap()
+            .range(start, end, self.now());
+        Iter::new(Source::Cask(self), Keys::new(keys))
+    }
+
+    /// Every key in the data store along with its value, in no particular order
+    ///
+    /// Values are read lazily, each one is the value of its key at the time the iterator reaches
+    /// it. Keys removed in the meantime are skipped, keys inserted after this is called are not
+    /// part of the iteration.
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{Cask, test::TestFileSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    ///     let cask: Cask<TestFileSystem> = Cask::new("")?;
+    ///     cask.insert("hello", "world")?;
+    ///     for entry in cask.iter() {
+    ///         let (key, value) = entry?;
+    ///         assert_eq!((key.as_slice(), value.as_slice()), (&b"hello"[..], &b"world"[..]));
+    ///     }
+    ///     # Ok(())
+    /// # }
+    /// ```
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter::new(Source::Cask(self), self.keys())
+    }
+
+    /// Number of keys in the data store
+    pub fn len(&self) -> usize {
+        self.inner.keydir.read().unwrap().len(self.now())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether `key` exists, without reading its value
+    pub fn contains_key<K>(&self, key: &K) -> bool
+    where
+        K: AsRef<[u8]>,
+    {
+        let keydir = self.inner.keydir.read().unwrap();
+        keydir.get_live(key.as_ref(), self.now()).is_some()
+    }
+}
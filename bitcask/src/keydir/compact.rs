@@ -0,0 +1,318 @@
+//! KeyDir storage with a small, fixed cost per key
+//!
+//! Keys are stored back to back in a single arena, and their entries packed into a dense array of
+//! slots. An open addressing hash table with linear probing maps keys to their slot, at four bytes
+//! per bucket. Removing a key moves the last slot into its place and leaves the key behind in the
+//! arena, which gets compacted once half of it is garbage. Few entries expire, so expiry times
+//! live in a separate map. The arrays grow in small steps rather than doubling, which keeps their
+//! unused capacity low.
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    mem,
+    ops::Range,
+};
+
+use crate::{
+    fs::{Fd, Offset},
+    CacheEntry,
+};
+
+/// Marks a bucket without a slot
+const EMPTY: u32 = u32::MAX;
+
+/// Arenas with less garbage than this are not worth compacting
+const MIN_GARBAGE: usize = 4096;
+
+/// Set in [`Slot::key`] if the entry has an expiry time
+const EXPIRES: u64 = 1 << 63;
+
+/// A packed [`CacheEntry`] along with the location of its key in the arena
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Slot {
+    /// Whether the entry expires in the top bit, the start of the key in the arena in the next
+    /// 47 bits and its length in the lower 16
+    key: u64,
+    fd: u32,
+    value_size: u32,
+    offset: u64,
+}
+
+impl Slot {
+    fn key(self) -> Range<usize> {
+        let start = ((self.key & !EXPIRES) >> 16) as usize;
+        start..start + (self.key & 0xffff) as usize
+    }
+
+    fn set_key(&mut self, key: Range<usize>) {
+        debug_assert!(key.start < 1 << 47 && key.len() <= u16::MAX as usize);
+        self.key = self.key & EXPIRES | (key.start as u64) << 16 | key.len() as u64;
+    }
+
+    fn expires(self) -> bool {
+        self.key & EXPIRES != 0
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CompactMap {
+    /// Index into `slots` for every bucket, or `EMPTY`. The number of buckets is zero or a power
+    /// of two.
+    buckets: Vec<u32>,
+    slots: Vec<Slot>,
+    /// Expiry times of the slots which have one, by index
+    expiring: HashMap<u32, u64>,
+    arena: Vec<u8>,
+    /// Bytes in the arena which belong to removed keys
+    garbage: usize,
+    hasher: RandomState,
+}
+
+impl CompactMap {
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<CacheEntry> {
+        let bucket = self.find(key).ok()?;
+        Some(self.cache_entry(self.buckets[bucket]))
+    }
+
+    pub fn insert(&mut self, key: &[u8], cache_entry: CacheEntry) -> Option<CacheEntry> {
+        if (self.slots.len() + 1) * 4 > self.buckets.len() * 3 {
+            self.grow();
+        }
+
+        let bucket = match self.find(key) {
+            Ok(bucket) => {
+                let index = self.buckets[bucket];
+                let old = self.cache_entry(index);
+                self.set(index, cache_entry);
+                return Some(old);
+            }
+            Err(bucket) => bucket,
+        };
+
+        let index = u32::try_from(self.slots.len())
+            .ok()
+            .filter(|index| *index != EMPTY)
+            .expect("More than 2^32 - 1 keys");
+        let start = self.arena.len();
+        reserve(&mut self.arena, key.len());
+        self.arena.extend_from_slice(key);
+
+        let mut slot = Slot {
+            key: 0,
+            fd: 0,
+            value_size: 0,
+            offset: 0,
+        };
+        slot.set_key(start..self.arena.len());
+        reserve(&mut self.slots, 1);
+        self.slots.push(slot);
+        self.set(index, cache_entry);
+        self.buckets[bucket] = index;
+        None
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<CacheEntry> {
+        let bucket = self.find(key).ok()?;
+        let index = self.buckets[bucket];
+        self.clear_bucket(bucket);
+
+        let removed = self.cache_entry(index);
+        self.garbage += self.slots[index as usize].key().len();
+        self.expiring.remove(&index);
+        self.slots.swap_remove(index as usize);
+
+        // The last slot took the place of the removed one, point its bucket at the new index
+        let moved = self.slots.len() as u32;
+        if index < moved {
+            let mut bucket = self.home(&self.arena[self.slots[index as usize].key()]);
+            while self.buckets[bucket] != moved {
+                bucket = self.next(bucket);
+            }
+            self.buckets[bucket] = index;
+            if let Some(expires_at) = self.expiring.remove(&moved) {
+                self.expiring.insert(index, expires_at);
+            }
+        }
+
+        if self.garbage >= MIN_GARBAGE && self.garbage * 2 >= self.arena.len() {
+            self.compact_arena();
+        }
+        Some(removed)
+    }
+
+    /// Every key along with its entry, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], CacheEntry)> {
+        (0..self.slots.len() as u32).map(|index| {
+            let key = self.slots[index as usize].key();
+            (&self.arena[key], self.cache_entry(index))
+        })
+    }
+
+    /// Bytes allocated for the map
+    pub fn memory(&self) -> usize {
+        // The expiry map allocates a control byte per bucket on top of the entry
+        let expiring = self.expiring.capacity() * (mem::size_of::<(u32, u64)>() + 1);
+        self.buckets.capacity() * mem::size_of::<u32>()
+            + self.slots.capacity() * mem::size_of::<Slot>()
+            + self.arena.capacity()
+            + expiring
+    }
+
+    fn cache_entry(&self, index: u32) -> CacheEntry {
+        let slot = self.slots[index as usize];
+        let expires_at = match slot.expires() {
+            true => self.expiring[&index],
+            false => 0,
+        };
+
+        CacheEntry {
+            fd: Fd::from_index(slot.fd as usize),
+            value_size: slot.value_size,
+            offset: Offset(slot.offset as usize),
+            expires_at,
+        }
+    }
+
+    fn set(&mut self, index: u32, cache_entry: CacheEntry) {
+        let slot = &mut self.slots[index as usize];
+        slot.fd = u32::try_from(cache_entry.fd.index()).expect("More than 2^32 data files");
+        slot.value_size = cache_entry.value_size;
+        slot.offset = cache_entry.offset.0 as u64;
+
+        if cache_entry.expires_at == 0 {
+            slot.key &= !EXPIRES;
+            self.expiring.remove(&index);
+        } else {
+            slot.key |= EXPIRES;
+            self.expiring.insert(index, cache_entry.expires_at);
+        }
+    }
+
+    /// Bucket holding `key`, or the empty bucket it would go into
+    fn find(&self, key: &[u8]) -> Result<usize, usize> {
+        if self.buckets.is_empty() {
+            return Err(0);
+        }
+
+        let mut bucket = self.home(key);
+        loop {
+            match self.buckets[bucket] {
+                EMPTY => return Err(bucket),
+                index if &self.arena[self.slots[index as usize].key()] == key => return Ok(bucket),
+                _ => bucket = self.next(bucket),
+            }
+        }
+    }
+
+    /// Bucket `key` hashes to, the first one probed for it
+    fn home(&self, key: &[u8]) -> usize {
+        self.hasher.hash_one(key) as usize & (self.buckets.len() - 1)
+    }
+
+    fn next(&self, bucket: usize) -> usize {
+        (bucket + 1) & (self.buckets.len() - 1)
+    }
+
+    /// Empties `bucket`, shifting later buckets of the same probe sequence back into it
+    fn clear_bucket(&mut self, mut hole: usize) {
+        let mask = self.buckets.len() - 1;
+        let mut bucket = self.next(hole);
+        while self.buckets[bucket] != EMPTY {
+            let home = self.home(&self.arena[self.slots[self.buckets[bucket] as usize].key()]);
+            // The slot may only move back if the hole lies between its home and where it is now
+            if bucket.wrapping_sub(home) & mask >= bucket.wrapping_sub(hole) & mask {
+                self.buckets[hole] = self.buckets[bucket];
+                hole = bucket;
+            }
+            bucket = self.next(bucket);
+        }
+        self.buckets[hole] = EMPTY;
+    }
+
+    /// Doubles the number of buckets and redistributes the slots
+    fn grow(&mut self) {
+        self.buckets = vec![EMPTY; (self.buckets.len() * 2).max(16)];
+        for index in 0..self.slots.len() {
+            let mut bucket = self.home(&self.arena[self.slots[index].key()]);
+            while self.buckets[bucket] != EMPTY {
+                bucket = self.next(bucket);
+            }
+            self.buckets[bucket] = index as u32;
+        }
+    }
+
+    /// Copies the keys still in use into a new arena
+    fn compact_arena(&mut self) {
+        let mut arena = Vec::new();
+        reserve(&mut arena, self.arena.len() - self.garbage);
+        for slot in &mut self.slots {
+            let key = slot.key();
+            let start = arena.len();
+            arena.extend_from_slice(&self.arena[key]);
+            slot.set_key(start..arena.len());
+        }
+        self.arena = arena;
+        self.garbage = 0;
+    }
+}
+
+/// Makes room for `additional` more elements, growing by a quarter at a time
+fn reserve<T>(vec: &mut Vec<T>, additional: usize) {
+    if vec.capacity() - vec.len() < additional {
+        vec.reserve_exact(additional.max(vec.len() / 4).max(16));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::CompactMap;
+    use crate::{
+        fs::{Fd, Offset},
+        CacheEntry,
+    };
+
+    fn entry(n: usize) -> CacheEntry {
+        CacheEntry {
+            fd: Fd::from_index(n % 7),
+            value_size: n as u32,
+            offset: Offset(n * 31),
+            expires_at: if n.is_multiple_of(4) { n as u64 + 1 } else { 0 },
+        }
+    }
+
+    #[test]
+    fn compact_map_matches_hash_map() {
+        let mut map = CompactMap::default();
+        let mut expected = HashMap::new();
+
+        // Enough churn to grow the table, shift probe sequences and compact the arena
+        for round in 0..3 {
+            for n in 0..5000 {
+                let key = format!("key-{}", n * 7919 % 5003).into_bytes();
+                if (n + round) % 3 == 0 {
+                    assert_eq!(map.remove(&key), expected.remove(&key));
+                } else {
+                    let cache_entry = entry(n + round);
+                    assert_eq!(
+                        map.insert(&key, cache_entry),
+                        expected.insert(key, cache_entry)
+                    );
+                }
+            }
+        }
+
+        assert_eq!(map.len(), expected.len());
+        for (key, cache_entry) in &expected {
+            assert_eq!(map.get(key), Some(*cache_entry));
+        }
+        assert_eq!(map.get(b"missing"), None);
+        assert_eq!(map.iter().count(), expected.len());
+    }
+}
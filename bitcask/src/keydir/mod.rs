@@ -0,0 +1,223 @@
+//! In-memory index from keys to the location of their latest entry
+mod compact;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+    ops::Bound,
+};
+
+use crate::CacheEntry;
+use compact::CompactMap;
+
+/// Data structure backing the KeyDir
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyDirKind {
+    /// Fastest point lookups. Range and prefix scans have to look at every key.
+    #[default]
+    Hash,
+
+    /// Keeps keys sorted, so that range and prefix scans only look at the keys they return.
+    Ordered,
+
+    /// Packs keys and their locations into a few large allocations, which takes a fraction of
+    /// the memory of the other kinds at the cost of somewhat slower writes. Range and prefix scans
+    /// have to look at every key.
+    Compact,
+}
+
+/// Memory taken up by the KeyDir, see [`Cask::keydir_memory`](crate::Cask::keydir_memory)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyDirMemory {
+    /// Number of keys, including expired ones which have not been merged away yet
+    pub keys: usize,
+    /// Estimated heap usage in bytes, not counting the overhead of the allocator
+    pub bytes: usize,
+}
+
+impl KeyDirMemory {
+    pub fn bytes_per_key(&self) -> f64 {
+        if self.keys == 0 {
+            return 0.0;
+        }
+        self.bytes as f64 / self.keys as f64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum KeyDir {
+    Hash(HashMap<Vec<u8>, CacheEntry>),
+    Ordered(BTreeMap<Vec<u8>, CacheEntry>),
+    Compact(CompactMap),
+}
+
+impl KeyDir {
+    pub fn new(kind: KeyDirKind) -> Self {
+        match kind {
+            KeyDirKind::Hash => KeyDir::Hash(HashMap::new()),
+            KeyDirKind::Ordered => KeyDir::Ordered(BTreeMap::new()),
+            KeyDirKind::Compact => KeyDir::Compact(CompactMap::default()),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<CacheEntry> {
+        match self {
+            KeyDir::Hash(map) => map.get(key).copied(),
+            KeyDir::Ordered(map) => map.get(key).copied(),
+            KeyDir::Compact(map) => map.get(key),
+        }
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, cache_entry: CacheEntry) -> Option<CacheEntry> {
+        match self {
+            KeyDir::Hash(map) => map.insert(key, cache_entry),
+            KeyDir::Ordered(map) => map.insert(key, cache_entry),
+            KeyDir::Compact(map) => map.insert(&key, cache_entry),
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<CacheEntry> {
+        match self {
+            KeyDir::Hash(map) => map.remove(key),
+            KeyDir::Ordered(map) => map.remove(key),
+            KeyDir::Compact(map) => map.remove(key),
+        }
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Looks up `key`, unless its entry has expired at `now`
+    ///
+    /// Expired entries stay in the KeyDir until a merge drops them, so every lookup on behalf of
+    /// a user goes through here.
+    pub fn get_live(&self, key: &[u8], now: u64) -> Option<CacheEntry> {
+        self.get(key)
+            .filter(|cache_entry| !cache_entry.is_expired(now))
+    }
+
+    /// Number of keys which have not expired at `now`
+    pub fn len(&self, now: u64) -> usize {
+        self.entries()
+            .filter(|(_, cache_entry)| !cache_entry.is_expired(now))
+            .count()
+    }
+
+    /// Copies out every key which has not expired at `now`, in no particular order
+    pub fn keys(&self, now: u64) -> Vec<Vec<u8>> {
+        self.entries()
+            .filter(|(_, cache_entry)| !cache_entry.is_expired(now))
+            .map(|(key, _)| key.to_vec())
+            .collect()
+    }
+
+    /// Copies out the keys within the given bounds which have not expired at `now`, in ascending
+    /// order
+    pub fn range(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, now: u64) -> Vec<Vec<u8>> {
+        let live = |(key, cache_entry): (&[u8], CacheEntry)| {
+            (!cache_entry.is_expired(now)).then(|| key.to_vec())
+        };
+        match self {
+            KeyDir::Ordered(map) => {
+                // BTreeMap panics on ranges that end before they start
+                if is_empty_range(&start, &end) {
+                    return Vec::new();
+                }
+                map.range((start, end))
+                    .map(|(key, cache_entry)| (key.as_slice(), *cache_entry))
+                    .filter_map(live)
+                    .collect()
+            }
+            KeyDir::Hash(_) | KeyDir::Compact(_) => {
+                let range = (start, end);
+                let mut keys: Vec<Vec<u8>> = self
+                    .entries()
+                    .filter(|(key, _)| range_contains(&range, key))
+                    .filter_map(live)
+                    .collect();
+                keys.sort_unstable();
+                keys
+            }
+        }
+    }
+
+    /// Number of keys along with an estimate of the memory they take up
+    pub fn memory(&self) -> KeyDirMemory {
+        // Keys are stored in their own allocation, next to the entry
+        const ENTRY: usize = mem::size_of::<(Vec<u8>, CacheEntry)>();
+        let key_bytes = || self.entries().map(|(key, _)| key.len()).sum::<usize>();
+
+        let (keys, bytes) = match self {
+            // One control byte per bucket
+            KeyDir::Hash(map) => (map.len(), map.capacity() * (ENTRY + 1) + key_bytes()),
+            // Nodes are about two thirds full on average
+            KeyDir::Ordered(map) => (map.len(), map.len() * ENTRY * 3 / 2 + key_bytes()),
+            KeyDir::Compact(map) => (map.len(), map.memory()),
+        };
+        KeyDirMemory { keys, bytes }
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (&[u8], CacheEntry)> + '_> {
+        match self {
+            KeyDir::Hash(map) => Box::new(
+                map.iter()
+                    .map(|(key, cache_entry)| (key.as_slice(), *cache_entry)),
+            ),
+            KeyDir::Ordered(map) => Box::new(
+                map.iter()
+                    .map(|(key, cache_entry)| (key.as_slice(), *cache_entry)),
+            ),
+            KeyDir::Compact(map) => Box::new(map.iter()),
+        }
+    }
+}
+
+fn range_contains(range: &(Bound<Vec<u8>>, Bound<Vec<u8>>), key: &[u8]) -> bool {
+    let after_start = match &range.0 {
+        Bound::Included(start) => key >= start.as_slice(),
+        Bound::Excluded(start) => key > start.as_slice(),
+        Bound::Unbounded => true,
+    };
+    let before_end = match &range.1 {
+        Bound::Included(end) => key <= end.as_slice(),
+        Bound::Excluded(end) => key < end.as_slice(),
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+fn is_empty_range(start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// Smallest key that is larger than every key starting with `prefix`, if there is one
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::prefix_end;
+
+    #[test]
+    fn prefix_end_skips_every_key_with_the_prefix() {
+        assert_eq!(prefix_end(b"user/"), Some(b"user0".to_vec()));
+        assert_eq!(prefix_end(&[b'a', 0xff, 0xff]), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(&[0xff]), None);
+        assert_eq!(prefix_end(b""), None);
+    }
+}
@@ -1,4 +1,5 @@
 #![feature(error_generic_member_access)]
+#![feature(unix_file_vectored_at)]
 
 //! A embedded hash backed log structured key value store.
 //!
@@ -6,26 +7,74 @@
 //! threadsafe, and supports pluggable storage _and_ system interfaces. This allows us to implement
 //! deterministic tests.
 
+mod backup;
+mod batch;
+mod cache;
 mod compactor;
+mod compression;
+mod conditional;
+mod encryption;
 mod fs;
+mod iter;
+mod keydir;
+mod locks;
+mod merge;
 mod pool;
 mod repr;
+mod snapshot;
+mod stats;
 pub mod test;
 
-use compactor::Compactor;
-pub use fs::{ConcreteSystem, FileSystem};
+pub use backup::{restore_backup, BackupManifest};
+pub use batch::WriteBatch;
+pub use cache::CacheStats;
+use cache::ValueCache;
+pub use compression::{Compression, CompressionError};
+pub use encryption::{Encryption, EncryptionError};
+pub use fs::{ConcreteSystem, Fd, FileSystem, MappedSlice, MmapSystem, Offset};
+pub use iter::{Iter, Keys};
+use keydir::KeyDir;
+pub use keydir::{KeyDirKind, KeyDirMemory};
+pub use merge::{CompactionHandle, CompactionProgress};
 use pool::Pool;
+pub use snapshot::Snapshot;
+use snapshot::Snapshots;
+pub use stats::FileStats;
 
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, VecDeque},
     hash::Hash,
-    sync::{Arc, RwLock},
+    ops::Deref,
+    path::Path,
+    sync::{Arc, Mutex, RwLock, Weak},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 use bytemuck::PodCastError;
-use fs::{Fd, Fs, FsError, Offset};
-use repr::{Entry, EntryError, Header};
-use tracing::{debug, info, instrument};
+use fs::{Fs, FsError};
+use locks::KeyLocks;
+use merge::SHUTDOWN_POLL;
+use repr::{Entry, EntryError, FileHeader, Header, HintHeader};
+use stats::Accounting;
+use tracing::{debug, error, info, instrument, warn};
+
+/// When writes to the active file are durably persisted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every write
+    Always,
+
+    /// Sync after every `n` writes
+    EveryN(usize),
+
+    /// Sync once writes have waited for at least this long, from a timer on the thread pool
+    Interval(Duration),
+
+    /// Leave it to the operating system, only [`Cask::sync`] syncs
+    Never,
+}
 
 /// Knobs for tuning the behavior of the data store.
 #[derive(Debug, Clone)]
@@ -34,29 +83,110 @@ pub struct Config {
     ///
     /// Note: the actual size on the file will be one entry larger than this threshold.
     pub active_threshold: usize,
+
+    /// When writes are synced to disk. Writes which have not been synced yet can be lost if the
+    /// machine crashes.
+    pub sync: SyncPolicy,
+
+    /// Fraction of dead bytes in a single immutable file above which it gets merged.
+    pub merge_dead_ratio: f64,
+
+    /// Total number of dead bytes across the immutable files above which they get merged.
+    pub merge_dead_bytes: u64,
+
+    /// How often the background compaction loop checks the thresholds above.
+    pub merge_check_interval: Duration,
+
+    /// Data structure backing the KeyDir. An ordered KeyDir makes range and prefix scans cheap, a
+    /// compact one saves memory on large data stores.
+    pub keydir: KeyDirKind,
+
+    /// Codec new values are compressed with. Values written with a different codec still decode.
+    pub compression: Compression,
+
+    /// Values smaller than this many bytes are stored uncompressed.
+    pub compression_threshold: usize,
+
+    /// Keys to encrypt entries and hint files with. Data written without encryption stays
+    /// readable, and is encrypted once a merge copies it.
+    pub encryption: Option<Encryption>,
+
+    /// Upper bound of the total size of the values cached in memory, in bytes. `0` disables the
+    /// cache.
+    pub value_cache_size: usize,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             active_threshold: 4096,
+            sync: SyncPolicy::Always,
+            merge_dead_ratio: 0.5,
+            merge_dead_bytes: 64 * 1024 * 1024,
+            merge_check_interval: Duration::from_secs(60),
+            keydir: KeyDirKind::default(),
+            compression: Compression::default(),
+            compression_threshold: 128,
+            encryption: None,
+            value_cache_size: 0,
         }
     }
 }
 
-#[derive(Clone)]
-pub struct Cask<T> {
+pub struct Cask<T>
+where
+    T: System,
+{
     inner: Arc<Inner<T>>,
     config: Config,
 }
 
-struct Inner<T> {
+// Handles share the same data store, so cloning must not require the file system to be `Clone`
+impl<T> Clone for Cask<T>
+where
+    T: System,
+{
+    fn clone(&self) -> Self {
+        Cask {
+            inner: Arc::clone(&self.inner),
+            config: self.config.clone(),
+        }
+    }
+}
+
+struct Inner<T>
+where
+    T: System,
+{
     fs: Fs<T>,
     // This can be a RwLock
-    keydir: RwLock<HashMap<Vec<u8>, CacheEntry>>,
+    keydir: RwLock<KeyDir>,
+    /// Live and dead bytes per data file, only updated while holding the KeyDir write lock
+    stats: Mutex<Accounting>,
+    /// Serializes writes to the same key, see [`KeyLocks`]
+    key_locks: KeyLocks,
+    /// Held for as long as a compactor is running, merges must never run concurrently
+    compaction: Mutex<()>,
+    /// Open snapshots, see [`Snapshot`]
+    snapshots: Mutex<Snapshots>,
+    /// Recently read and written values, see [`ValueCache`]
+    cache: ValueCache,
     pool: Pool,
 }
 
+impl<T> Drop for Inner<T>
+where
+    T: System,
+{
+    fn drop(&mut self) {
+        // The last handle is gone, so nothing else is going to sync what the sync policy has
+        // not gotten around to yet
+        if let Err(err) = self.fs.sync_pending() {
+            error!(error = %err, "Unable to sync the active file on drop");
+        }
+    }
+}
+
 impl<T> Cask<T>
 where
     T: System,
@@ -65,45 +195,199 @@ where
     pub fn new_with_config(path: &str, config: Config) -> Result<Self, CaskError> {
         let fs_impl = T::init(path)?;
 
-        Ok(Cask::new_with_fs_impl(path, config, fs_impl)?)
+        Cask::new_with_fs_impl(path, config, fs_impl)
     }
 
     #[instrument(skip(fs_impl))]
     pub fn new_with_fs_impl(path: &str, config: Config, fs_impl: T) -> Result<Self, CaskError> {
-        let fs = Fs::new(fs_impl)?;
-
-        let size = fs.active_size()?;
-        // We already have an active db. Build KeyDir
-        let keydir = if size > 0 {
-            info!(file_size = size, "Active db exists");
-            let iterator = HeaderIter {
-                active_fd: fs.active_fd(),
-                fs: &fs,
-                current: Offset(0),
-            };
+        Cask::open(config, fs_impl, None)
+    }
 
-            let mut map = HashMap::new();
+    /// Opens the data store, with the KeyDir as of `as_of` if given
+    fn open(config: Config, fs_impl: T, as_of: Option<SystemTime>) -> Result<Self, CaskError> {
+        let fs = Fs::new(
+            fs_impl,
+            config.active_threshold,
+            config.sync,
+            config.encryption.clone(),
+        )?;
 
-            for entry in iterator {
-                let (key, cache_entry) = entry?;
-                map.insert(key, cache_entry);
-            }
+        let mut stats = Accounting::default();
+        let (keydir, active_size) = Cask::build_keydir(&fs, &mut stats, config.keydir, as_of)?;
 
-            // Update FS cursor to the end of the file
-            fs.update_cursor(fs.active_size()?);
-            map
-        } else {
-            HashMap::new()
-        };
+        // Resume appending after the last valid entry of the active file
+        fs.update_cursor(active_size);
 
-        Ok(Cask {
+        let cask = Cask {
             inner: Arc::new(Inner {
                 fs,
                 keydir: RwLock::new(keydir),
+                stats: Mutex::new(stats),
+                key_locks: KeyLocks::new(),
+                compaction: Mutex::new(()),
+                snapshots: Mutex::new(Snapshots::default()),
+                cache: ValueCache::new(config.value_cache_size),
                 pool: Pool::new(4),
             }),
             config,
-        })
+        };
+
+        // Without the timer, the last writes before a quiet period would wait for the next write.
+        // Views of the past never write, and the pool only spawns threads once it is given work,
+        // so they never start any.
+        if let (SyncPolicy::Interval(interval), None) = (cask.config.sync, as_of) {
+            let inner = Arc::downgrade(&cask.inner);
+            cask.inner.pool.execute(move || {
+                Cask::sync_loop(inner, interval);
+            });
+        }
+
+        Ok(cask)
+    }
+
+    /// Rebuilds the KeyDir by replaying every data file from oldest to newest.
+    ///
+    /// Later entries for a key supersede earlier ones, and tombstones as well as expired entries
+    /// remove the key altogether.
+    /// Immutable files are replayed from their hint file when one exists, which avoids reading
+    /// any values. Missing hint files are recreated along the way.
+    ///
+    /// If the process died in the middle of a write, the tail of the active file holds an
+    /// incomplete entry. It gets truncated away, and the size of the remaining valid prefix of the
+    /// active file is returned along with the KeyDir.
+    ///
+    /// The live and dead bytes of every replayed file are recorded into `stats`.
+    ///
+    /// With `as_of`, entries written after that time are skipped and expiry is judged at that
+    /// time, which yields the KeyDir as it was back then. The data store is only read from then,
+    /// so the data files are left exactly as they are: torn tails are skipped rather than
+    /// truncated, and no hint files are written.
+    #[instrument(skip(fs, stats))]
+    fn build_keydir(
+        fs: &Fs<T>,
+        stats: &mut Accounting,
+        kind: KeyDirKind,
+        as_of: Option<SystemTime>,
+    ) -> Result<(KeyDir, u64), CaskError> {
+        let mut map = KeyDir::new(kind);
+        let (cutoff, now) = match as_of {
+            Some(time) => (Some(repr::timestamp_of(time)?), repr::millis_of(time)?),
+            None => (None, repr::unix_millis(fs).unwrap_or(0)),
+        };
+        let is_future = |timestamp: u64| cutoff.is_some_and(|cutoff| timestamp > cutoff);
+        let read_only = as_of.is_some();
+        let active_fd = fs.active_fd();
+        let mut active_size = 0;
+
+        for fd in fs.files() {
+            Cask::check_format(fs, fd, fd == active_fd && !read_only)?;
+
+            if fd != active_fd {
+                if let Some(hints) = fs.read_hint(fd)? {
+                    if let Some(hints) = HintHeader::parse(&hints) {
+                        info!(fd = ?fd, "Replaying hint file");
+                        for (hint, key) in hints {
+                            if is_future(hint.timestamp) {
+                                continue;
+                            }
+                            let cache_entry = CacheEntry {
+                                fd,
+                                value_size: hint.value_size,
+                                offset: Offset(hint.offset as usize),
+                                expires_at: hint.expires_at,
+                            };
+                            // Expired entries are dead, just like tombstones
+                            let dead = hint.is_tombstone() || cache_entry.is_expired(now);
+                            apply_entry(&mut map, stats, key.into(), dead, cache_entry);
+                        }
+                        continue;
+                    }
+                    warn!(fd = ?fd, "Hint file is damaged, replaying data file instead");
+                }
+            }
+
+            info!(fd = ?fd, "Replaying data file");
+            let mut hints = Vec::new();
+            let mut iterator = HeaderIter::new(fs, fd)?;
+            for entry in iterator.by_ref() {
+                let (key, header, cache_entry) = entry?;
+                HintHeader::append(&mut hints, &header, &key, cache_entry.offset.0 as u64);
+                if is_future(header.timestamp) {
+                    continue;
+                }
+                let dead = header.is_tombstone() || header.is_expired(now);
+                apply_entry(&mut map, stats, key, dead, cache_entry);
+            }
+
+            match iterator.torn_tail() {
+                // Immutable files were sealed after a successful write, so this is not the result
+                // of a crash.
+                Some(offset) if fd != active_fd => {
+                    return Err(CaskError::Corruption { fd, offset })
+                }
+                Some(offset) if read_only => {
+                    warn!(
+                        fd = ?fd,
+                        offset = offset.0,
+                        file_size = iterator.file_size,
+                        "Skipping incomplete entry at the end of the active file"
+                    );
+                    active_size = offset.0 as u64;
+                }
+                Some(offset) => {
+                    warn!(
+                        fd = ?fd,
+                        offset = offset.0,
+                        file_size = iterator.file_size,
+                        "Truncating incomplete entry at the end of the active file"
+                    );
+                    fs.truncate(fd, offset.0 as u64)?;
+                    active_size = offset.0 as u64;
+                }
+                None if fd == active_fd => active_size = iterator.file_size,
+                None => {}
+            }
+
+            if fd == active_fd {
+                fs.restore_hints(hints);
+            } else if !read_only {
+                fs.write_hint(fd, &hints)?;
+            }
+        }
+
+        Ok((map, active_size))
+    }
+
+    /// Makes sure the data file `fd` is in the current format, before anything gets replayed
+    ///
+    /// An active file without a complete file header was created right before a crash. With
+    /// `repair`, it gets its file header written. Files of any other format are refused and left
+    /// untouched.
+    fn check_format(fs: &Fs<T>, fd: Fd, repair: bool) -> Result<(), CaskError> {
+        let size = fs.file_size(fd)?;
+        let mut buf = vec![0; size.min(FileHeader::LEN) as usize];
+        fs.get_chunk_fd(Offset(0), &mut buf, fd)?;
+
+        let current = FileHeader::current();
+        if size < FileHeader::LEN && current.serialize().starts_with(&buf) {
+            if repair {
+                fs.write_all_at(fd, current.serialize(), Offset(0))?;
+            }
+            return Ok(());
+        }
+
+        match FileHeader::parse(&buf) {
+            Some(header) if header.version == FileHeader::VERSION => Ok(()),
+            Some(header) => {
+                let version = header.version;
+                error!(fd = ?fd, version, "Data file was written in an unknown format version");
+                Err(CaskError::UnsupportedFormat { fd })
+            }
+            None => {
+                error!(fd = ?fd, "Data file does not start with a file header");
+                Err(CaskError::UnsupportedFormat { fd })
+            }
+        }
     }
 
     #[instrument]
@@ -111,25 +395,40 @@ where
         Cask::new_with_config(path, Config::default())
     }
 
+    /// Starts the background compaction loop on the thread pool
+    ///
+    /// The loop only holds a weak reference to the data store, and exits once every `Cask` handle
+    /// has been dropped.
     pub fn init(self) -> Self {
-        // todo: parameterize
-        for _ in 0..2 {
-            // Create a new Cask instance which is a copy of the innser struct to ensure that the
-            // whole clone is moved into each background thread closure.
-            let new_inner = self.inner.clone();
-            let new_cask = Cask {
-                inner: new_inner,
-                config: Config::default(),
-            };
+        // There is a single compaction loop, merges started by hand wait for it to finish through
+        // `Inner::compaction`
+        let inner = Arc::downgrade(&self.inner);
+        let config = self.config.clone();
 
-            self.inner.pool.execute(move || {
-                new_cask.compaction_loop();
-            });
-        }
+        self.inner.pool.execute(move || {
+            Cask::compaction_loop(inner, config);
+        });
 
         self
     }
 
+    /// Syncs the active file whenever writes have waited for `interval`
+    ///
+    /// Like the compaction loop, this only holds a weak reference to the data store.
+    #[instrument(skip(inner))]
+    fn sync_loop(inner: Weak<Inner<T>>, interval: Duration) {
+        while let Some(inner) = inner.upgrade() {
+            if let Err(err) = inner.fs.sync_due() {
+                error!(error = %err, "Unable to sync the active file");
+            }
+            drop(inner);
+
+            // Time might be controlled by a test clock, so keep checking at least every poll
+            thread::sleep(interval.min(SHUTDOWN_POLL));
+        }
+        info!("Data store dropped, exiting sync loop");
+    }
+
     /// Inserts a new entry into the data store
     ///
     /// The keys and values should be serializable, which is done via the `StoredData` trait.
@@ -148,29 +447,132 @@ where
         K: AsRef<[u8]> + Hash + Eq,
         V: AsRef<[u8]>,
     {
-        let entry = Entry::new_encoded(&key, &value)?;
-        let entry = self.inner.fs.write_entry(entry)?;
+        let _key_lock = self.inner.key_locks.lock(key.as_ref());
+        self.put_locked(key.as_ref(), value.as_ref(), None)
+    }
+
+    /// Inserts a new entry which expires once `ttl` has passed
+    ///
+    /// Expired entries behave as if they had been removed, and the next merge drops them from
+    /// disk. Time is measured by the [`ClockSource`] of the data store.
+    ///
+    /// ```rust
+    /// # use std::{error::Error, time::Duration};
+    /// # use bitcask::{Cask, CaskError, FileSystem, test::TestFileSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    ///     let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    ///     let cask = Cask::new_with_fs_impl("", Default::default(), test_fs.clone())?;
+    ///     cask.insert_with_ttl("session", "token", Duration::from_secs(30))?;
+    ///     assert_eq!(cask.get(&"session")?, "token".as_bytes());
+    ///
+    ///     test_fs.advance(Duration::from_secs(30));
+    ///     assert!(matches!(cask.get(&"session"), Err(CaskError::NotFound)));
+    ///     # Ok(())
+    /// # }
+    /// ```
+    pub fn insert_with_ttl<K, V>(&self, key: K, value: V, ttl: Duration) -> Result<(), CaskError>
+    where
+        K: AsRef<[u8]> + Hash + Eq,
+        V: AsRef<[u8]>,
+    {
+        let _key_lock = self.inner.key_locks.lock(key.as_ref());
+        self.put_locked(key.as_ref(), value.as_ref(), Some(ttl))
+    }
 
-        // A branch requring a mutex on every insert could get expensive
-        if self.inner.fs.active_size()? as usize >= self.config.active_threshold {
-            self.inner.fs.swap_active()?;
+    /// Appends a new value for `key` and points the KeyDir at it
+    ///
+    /// The value expires once `ttl` has passed, if one is given. The caller must hold the lock of
+    /// `key`.
+    fn put_locked(&self, key: &[u8], value: &[u8], ttl: Option<Duration>) -> Result<(), CaskError> {
+        let (encoded, flags) = self.encode_value(value);
+        let mut entry = Entry::new_encoded(&key, &encoded, &self.inner.fs)?.flagged(flags);
+        if let Some(ttl) = ttl {
+            entry = entry.expiring(ttl, &self.inner.fs)?;
         }
+        // Rotating the active file once it crosses the threshold is handled by the Fs layer
+        let entry = self.inner.fs.write_entry(entry)?;
+        self.inner.cache.insert(&entry, value);
 
         // TODO: Can we get away from allocating a whole new vec for every key?
         // IMO no? We need to own the data for the type in this container.
-        let key = key.as_ref().into();
-
-        self.inner
+        let mut keydir = self
+            .inner
             .keydir
             .write()
-            .expect("Unable to lock hashmap mutex")
-            .entry(key)
-            .and_modify(|cache_entry| *cache_entry = entry.clone())
-            .or_insert_with(|| entry);
+            .expect("Unable to lock hashmap mutex");
+        let mut stats = self.inner.stats.lock().unwrap();
+        apply_entry(&mut keydir, &mut stats, key.into(), false, entry);
+
+        Ok(())
+    }
+
+    /// Compresses `value` as configured, see [`Compression::encode`]
+    fn encode_value<'v>(&self, value: &'v [u8]) -> (Cow<'v, [u8]>, u8) {
+        self.config
+            .compression
+            .encode(value, self.config.compression_threshold)
+    }
+
+    /// Appends a tombstone for `key` and removes it from the KeyDir
+    ///
+    /// The caller must hold the lock of `key`.
+    fn delete_locked(&self, key: &[u8]) -> Result<(), CaskError> {
+        let tombstone = Entry::new_empty(&key, &self.inner.fs)?;
+        let entry = self.inner.fs.write_entry(tombstone)?;
+
+        let mut keydir = self.inner.keydir.write().unwrap();
+        let mut stats = self.inner.stats.lock().unwrap();
+        apply_entry(&mut keydir, &mut stats, key.into(), true, entry);
 
         Ok(())
     }
 
+    /// Reads the value of the entry at `cache_entry`
+    ///
+    /// The caller must hold the KeyDir lock the entry was looked up under, so that a merge can't
+    /// delete its file in the meantime.
+    fn read_value(&self, cache_entry: &CacheEntry) -> Result<Vec<u8>, CaskError> {
+        if let Some(value) = self.inner.cache.get(cache_entry) {
+            return Ok(value);
+        }
+
+        // The entry might live in an immutable file if the active file has been rotated since it
+        // was written, so always read from the file recorded in the KeyDir.
+        let mut buf = [0u8; Header::LEN as usize];
+        self.inner
+            .fs
+            .get_chunk_fd(cache_entry.offset, &mut buf, cache_entry.fd)?;
+        let header: &Header = bytemuck::try_from_bytes(&buf).map_err(CaskError::Cast)?;
+
+        let corruption = CaskError::Corruption {
+            fd: cache_entry.fd,
+            offset: cache_entry.offset,
+        };
+
+        // A damaged header could claim an arbitrary size, don't trust it over the KeyDir
+        if header.value_size != cache_entry.value_size {
+            return Err(corruption);
+        }
+
+        let data_len = header.data_size();
+        let mut buf = vec![0u8; data_len];
+        self.inner
+            .fs
+            .get_chunk_fd(cache_entry.data_offset(), &mut buf, cache_entry.fd)?;
+
+        if !header.verify(&buf) {
+            return Err(corruption);
+        }
+        if header.is_encrypted() {
+            buf = self.inner.fs.decrypt(&buf)?;
+        }
+
+        let value = compression::decode(header.flags, &buf[header.key_size as usize..])?;
+        self.inner.cache.insert(cache_entry, &value);
+
+        Ok(value)
+    }
+
     /// Gets an entry from the data store if it's present
     ///
     /// ```rust
@@ -188,23 +590,59 @@ where
         K: AsRef<[u8]> + Hash + Eq,
     {
         let entry = self.inner.keydir.read().unwrap();
-        let Some(cache_entry) = entry.get(key.as_ref()) else {
+        let Some(cache_entry) = entry.get_live(key.as_ref(), self.now()) else {
             return Err(CaskError::NotFound);
         };
 
-        let mut buf = [0u8; Header::LEN as usize];
-        self.inner.fs.get_chunk(cache_entry.offset, &mut buf)?;
-        let header: &Header = bytemuck::try_from_bytes(&buf).map_err(CaskError::Cast)?;
+        self.read_value(&cache_entry)
+    }
 
-        let data_len = header.data_size();
-        let mut buf = vec![0u8; data_len as usize];
-        self.inner
-            .fs
-            .get_chunk(cache_entry.data_offset(), &mut buf)?;
+    /// Gets an entry from the data store without copying its value, where possible
+    ///
+    /// Values in immutable files are borrowed straight from the map of the file if the file
+    /// system supports it, such as [`MmapSystem`]. Values which are compressed, encrypted or still
+    /// in the active file are read just like [`Cask::get`] does.
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{Cask, MmapSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    /// # let dir = std::env::temp_dir().join(format!("bitcask-get-ref-{}", std::process::id()));
+    ///     let cask: Cask<MmapSystem> = Cask::new(dir.to_str().unwrap())?;
+    ///     cask.insert("hello", "world")?;
+    ///     assert_eq!(&*cask.get_ref(&"hello")?, "world".as_bytes());
+    /// # std::fs::remove_dir_all(dir)?;
+    ///     # Ok(())
+    /// # }
+    /// ```
+    pub fn get_ref<K>(&self, key: &K) -> Result<ValueRef, CaskError>
+    where
+        K: AsRef<[u8]> + Hash + Eq,
+    {
+        let key = key.as_ref();
+        let keydir = self.inner.keydir.read().unwrap();
+        let Some(cache_entry) = keydir.get_live(key, self.now()) else {
+            return Err(CaskError::NotFound);
+        };
 
-        let value = &buf[header.key_size as usize..];
+        let entry_size = cache_entry.entry_size(key.len()) as usize;
+        if let Some(mapped) =
+            self.inner
+                .fs
+                .get_mapped(cache_entry.offset, entry_size, cache_entry.fd)
+        {
+            let (header, data) = mapped.split_at(Header::LEN as usize);
+            let header: &Header = bytemuck::try_from_bytes(header).map_err(CaskError::Cast)?;
+            let encoded = header.is_encrypted() || header.flags & Header::LZ4 != 0;
 
-        Ok(value.into())
+            // Anything else has to be decoded, or is corrupt and left to `read_value` to report
+            if header.value_size == cache_entry.value_size && header.verify(data) && !encoded {
+                let value = Header::LEN as usize + header.key_size as usize..mapped.len();
+                return Ok(ValueRef::Mapped(mapped.slice(value)));
+            }
+        }
+
+        Ok(ValueRef::Owned(self.read_value(&cache_entry)?))
     }
 
     /// Delete an entry from the data store
@@ -214,91 +652,346 @@ where
     {
         // TODO: Can we get away from allocating a whole vec for every key?
         // IMO no? We need to own the data for the type in this container.
-        let tombstone = Entry::new_empty(key);
-        let key = key.as_ref().into();
+        let key = key.as_ref();
+        let _key_lock = self.inner.key_locks.lock(key);
 
-        if let Some(_) = self.inner.keydir.write().unwrap().remove(key) {
-            let _entry = self.inner.fs.write_entry(tombstone)?;
+        // Removing a key that does not exist does not need a tombstone
+        if self.inner.keydir.read().unwrap().contains_key(key) {
+            self.delete_locked(key)?;
         }
         Ok(())
     }
-}
 
-// Compaction impl
-impl<T> Cask<T>
-where
-    T: System,
-{
-    #[instrument(skip(self))]
-    pub(crate) fn compaction_loop(self: Self) {
-        let mut compactor = Compactor::new();
+    /// Durably persists every write made so far, regardless of the configured [`SyncPolicy`]
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{Cask, test::TestFileSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    ///     let cask: Cask<TestFileSystem> = Cask::new("")?;
+    ///     cask.insert("hello", "world")?;
+    ///     cask.sync()?;
+    ///     # Ok(())
+    /// # }
+    /// ```
+    pub fn sync(&self) -> Result<(), CaskError> {
+        Ok(self.inner.fs.sync()?)
+    }
+
+    /// Writes a consistent copy of the data store into the empty directory `dest`, while it keeps
+    /// serving reads and writes
+    ///
+    /// The copy contains every write made before this is called, and can be opened like any
+    /// other data store. Immutable files are hard linked into `dest` rather than copied, so it
+    /// should live on the same file system. Writes wait while the active file is copied, and
+    /// merges wait for the whole checkpoint.
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{Cask, ConcreteSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    /// # let dir = std::env::temp_dir().join(format!("bitcask-checkpoint-{}", std::process::id()));
+    /// # let (path, dest) = (dir.join("db"), dir.join("checkpoint"));
+    ///     let cask: Cask<ConcreteSystem> = Cask::new(path.to_str().unwrap())?;
+    ///     cask.insert("hello", "world")?;
+    ///     cask.checkpoint(&dest)?;
+    ///
+    ///     let copy: Cask<ConcreteSystem> = Cask::new(dest.to_str().unwrap())?;
+    ///     assert_eq!(copy.get(&"hello")?, "world".as_bytes());
+    /// # std::fs::remove_dir_all(dir)?;
+    ///     # Ok(())
+    /// # }
+    /// ```
+    #[instrument(skip(self, dest), fields(dest = ?dest.as_ref()))]
+    pub fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<(), CaskError> {
+        // Merges create and delete data files, keep them from running while the files are copied
+        let _no_merge = self.inner.compaction.lock().unwrap();
+        self.inner.fs.checkpoint(dest.as_ref(), &BTreeMap::new())?;
+        Ok(())
+    }
+
+    /// Current time in milliseconds since the unix epoch, for deciding whether entries expired
+    fn now(&self) -> u64 {
+        // Nothing can have expired if the clock is set before the unix epoch
+        repr::unix_millis(&self.inner.fs).unwrap_or(0)
+    }
+
+    /// Live and dead bytes of every data file
+    ///
+    /// Bytes become dead once the entry they belong to is overwritten or removed. Merging a file
+    /// with a lot of dead bytes reclaims them.
+    pub fn file_stats(&self) -> HashMap<Fd, FileStats> {
+        self.inner.stats.lock().unwrap().files()
+    }
+
+    /// Number of keys in the KeyDir and the memory it takes up
+    ///
+    /// Use this to size [`Config::keydir`] for large data stores, [`KeyDirKind::Compact`] takes
+    /// the least memory per key.
+    pub fn keydir_memory(&self) -> KeyDirMemory {
+        self.inner.keydir.read().unwrap().memory()
+    }
 
-        let operation = compactor
-            .poll_transmit()
-            .expect("First poll is always present");
+    /// Hit and miss counters of the value cache, see [`Config::value_cache_size`]
+    pub fn cache_stats(&self) -> CacheStats {
+        self.inner.cache.stats()
     }
 }
 
+/// An entry read back from a data file: its key, header and location
+pub(crate) type ReplayedEntry = (Vec<u8>, Header, CacheEntry);
+
+/// Iterates over the headers of every entry in a single data file.
+///
+/// Entries of a write batch are only yielded once the record committing the batch has been read.
+/// Iteration stops early if the file ends in an entry that is incomplete or fails its checksum,
+/// with nothing valid behind it, or in a batch that was never committed. The offset of the first
+/// entry that has to be dropped is then available through [`HeaderIter::torn_tail`].
 pub(crate) struct HeaderIter<'cask, T> {
     fs: &'cask Fs<T>,
-    active_fd: Fd,
+    fd: Fd,
     current: Offset,
+    file_size: u64,
+    torn: Option<Offset>,
+    /// Entries of a batch whose commit record has not been read yet
+    batch: Vec<ReplayedEntry>,
+    /// Entries of a committed batch which have not been yielded yet
+    committed: VecDeque<ReplayedEntry>,
 }
 
-impl<'cask, T> Iterator for HeaderIter<'cask, T>
+impl<'cask, T> HeaderIter<'cask, T>
 where
     T: System,
 {
-    type Item = Result<(Vec<u8>, CacheEntry), CaskError>;
+    pub fn new(fs: &'cask Fs<T>, fd: Fd) -> Result<Self, CaskError> {
+        let file_size = fs.file_size(fd)?;
+        Ok(HeaderIter {
+            fs,
+            fd,
+            current: Offset(FileHeader::LEN as usize),
+            file_size,
+            torn: None,
+            batch: Vec::new(),
+            committed: VecDeque::new(),
+        })
+    }
 
-    #[instrument(skip(self))]
-    fn next(&mut self) -> Option<Self::Item> {
-        let file_size = match self.fs.active_size() {
-            Ok(size) => size,
-            Err(err) => return Some(Err(err.into())),
-        };
+    /// Offset of an incomplete entry at the end of the file, if one was found
+    pub fn torn_tail(&self) -> Option<Offset> {
+        self.torn
+    }
 
-        if self.current.0 < file_size as usize {
+    /// Ends the iteration at an entry which runs past the end of the file or fails its checksum
+    ///
+    /// A crash only ever tears the last write, which may leave it incomplete or followed by
+    /// garbage such as zeros from preallocated blocks. So this is a torn tail unless a valid entry
+    /// starts anywhere behind the current one. Then the entry itself is damaged, and truncating
+    /// the file there would throw away every entry after it.
+    fn torn_or_corrupt(&mut self) -> Option<Result<ReplayedEntry, CaskError>> {
+        match self.valid_entry_follows() {
+            Ok(false) => {
+                self.torn = Some(self.current);
+                None
+            }
+            Ok(true) => Some(Err(CaskError::Corruption {
+                fd: self.fd,
+                offset: self.current,
+            })),
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    /// Whether any offset after the current one holds an entry with a valid checksum
+    fn valid_entry_follows(&self) -> Result<bool, CaskError> {
+        let start = self.current.0 + 1;
+        let mut buf = vec![0u8; (self.file_size as usize).saturating_sub(start)];
+        self.fs.get_chunk_fd(Offset(start), &mut buf, self.fd)?;
+
+        for offset in 0..buf.len() {
+            let rest = &buf[offset..];
+            let Some(header) = rest.get(..Header::LEN as usize) else {
+                break;
+            };
+            let header: Header = *bytemuck::try_from_bytes(header).map_err(CaskError::Cast)?;
+            let Some(data) = rest.get(Header::LEN as usize..header.entry_size()) else {
+                continue;
+            };
+            if header.verify(data) {
+                warn!(
+                    fd = ?self.fd,
+                    offset = self.current.0,
+                    valid = start + offset,
+                    "Valid entry found behind a damaged entry"
+                );
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Reads the next entry in the file, returning its |key|value| data
+    fn read_entry(&mut self) -> Option<Result<ReplayedEntry, CaskError>> {
+        if self.torn.is_none() && self.current.0 < self.file_size as usize {
             debug!(offset = self.current.0, "reading another entry");
 
+            let remaining = self.file_size as usize - self.current.0;
+            if remaining < Header::LEN as usize {
+                self.torn = Some(self.current);
+                return None;
+            }
+
             let mut buf = [0u8; Header::LEN as usize];
-            match self.fs.get_chunk(self.current, &mut buf) {
+            match self.fs.get_chunk_fd(self.current, &mut buf, self.fd) {
                 Ok(()) => (),
                 Err(err) => return Some(Err(err.into())),
             };
-            let header: &Header = match bytemuck::try_from_bytes(&buf) {
-                Ok(header) => header,
+            let header: Header = match bytemuck::try_from_bytes(&buf) {
+                Ok(header) => *header,
                 Err(err) => return Some(Err(CaskError::Cast(err))),
             };
 
-            let mut buf = vec![0u8; header.key_size as usize];
-            match self
-                .fs
-                .get_chunk(Offset(self.current.0 + Header::LEN as usize), &mut buf)
-            {
+            if header.entry_size() > remaining {
+                return self.torn_or_corrupt();
+            }
+
+            // The whole entry needs to be read to validate its checksum
+            let mut buf = vec![0u8; header.data_size()];
+            match self.fs.get_chunk_fd(
+                Offset(self.current.0 + Header::LEN as usize),
+                &mut buf,
+                self.fd,
+            ) {
                 Ok(()) => (),
                 Err(err) => return Some(Err(err.into())),
             };
 
+            if !header.verify(&buf) {
+                return self.torn_or_corrupt();
+            }
+            // Callers expect the plaintext key at the start of the data
+            if header.is_encrypted() {
+                buf = match self.fs.decrypt(&buf) {
+                    Ok(buf) => buf,
+                    Err(err) => return Some(Err(err.into())),
+                };
+            }
+
             let cache_entry = CacheEntry {
-                fd: self.active_fd,
+                fd: self.fd,
                 value_size: header.value_size,
                 offset: self.current,
-                timestamp: header.timestamp,
+                expires_at: header.expires_at,
             };
 
             self.current = Offset(self.current.0 + header.entry_size());
 
-            return Some(Ok((buf, cache_entry)));
+            return Some(Ok((buf, header, cache_entry)));
         }
 
         None
     }
 }
 
+impl<'cask, T> Iterator for HeaderIter<'cask, T>
+where
+    T: System,
+{
+    type Item = Result<ReplayedEntry, CaskError>;
+
+    #[instrument(skip(self))]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.committed.pop_front() {
+                return Some(Ok(entry));
+            }
+
+            let batch_start = self
+                .batch
+                .first()
+                .map(|(_, _, cache_entry)| cache_entry.offset);
+            let (mut buf, header, cache_entry) = match self.read_entry() {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    // Batches are written in one go, so a missing commit record at the end of the
+                    // file means the process died while writing it.
+                    if let Some(offset) = batch_start {
+                        debug!(offset = offset.0, "Dropping uncommitted batch");
+                        self.torn = Some(offset);
+                        self.batch.clear();
+                    }
+                    return None;
+                }
+            };
+
+            if header.is_batch_commit() {
+                let count = buf
+                    .get(..4)
+                    .map(|count| u32::from_le_bytes(count.try_into().unwrap()));
+                if count != Some(self.batch.len() as u32) {
+                    return Some(Err(CaskError::Corruption {
+                        fd: self.fd,
+                        offset: batch_start.unwrap_or(cache_entry.offset),
+                    }));
+                }
+                self.committed.extend(self.batch.drain(..));
+                continue;
+            }
+
+            buf.truncate(header.key_size as usize);
+            if header.in_batch() {
+                self.batch.push((buf, header, cache_entry));
+                continue;
+            }
+
+            // Nothing else is ever written between the entries of a batch and its commit record
+            if let Some(offset) = batch_start {
+                return Some(Err(CaskError::Corruption {
+                    fd: self.fd,
+                    offset,
+                }));
+            }
+
+            return Some(Ok((buf, header, cache_entry)));
+        }
+    }
+}
+
+/// Applies a replayed entry to the KeyDir being rebuilt
+fn apply_entry(
+    map: &mut KeyDir,
+    stats: &mut Accounting,
+    key: Vec<u8>,
+    tombstone: bool,
+    cache_entry: CacheEntry,
+) {
+    let key_len = key.len();
+    let old = if tombstone {
+        stats.add_dead(cache_entry.fd, cache_entry.entry_size(key_len));
+        map.remove(&key)
+    } else {
+        stats.add_live(cache_entry.fd, cache_entry.entry_size(key_len));
+        map.insert(key, cache_entry)
+    };
+
+    if let Some(old) = old {
+        stats.supersede(old.fd, old.entry_size(key_len));
+    }
+}
+
 pub trait System: FileSystem + ClockSource + Send + Sync + 'static {}
 
-pub trait ClockSource {}
+/// Source of time for the data store
+///
+/// Every timestamp and timer in the data store goes through this trait, which allows tests to
+/// control the passage of time.
+pub trait ClockSource {
+    /// Wall-clock time, recorded as the timestamp of every entry
+    fn system_time(&self) -> SystemTime;
+
+    /// Monotonic time, used for timers such as the compaction interval
+    fn instant(&self) -> Instant;
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum CaskError {
@@ -311,20 +1004,78 @@ pub enum CaskError {
     #[error("Encoding error: {0}")]
     Entry(#[from] EntryError),
 
+    #[error("Compression error: {0}")]
+    Compression(#[from] CompressionError),
+
+    #[error("Encryption error: {0}")]
+    Encryption(#[from] EncryptionError),
+
     #[error("Entry not found")]
     NotFound,
+
+    #[error("Compaction was cancelled")]
+    Cancelled,
+
+    #[error("The condition of a conditional write does not hold")]
+    PreconditionFailed,
+
+    #[error("Checksum mismatch for entry in {fd} at offset {}", offset.0)]
+    Corruption { fd: Fd, offset: Offset },
+
+    /// The data file was written by an incompatible version, or is no data file at all. It is left
+    /// untouched.
+    #[error("Data file {fd} is not in a supported format")]
+    UnsupportedFormat { fd: Fd },
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+/// A value returned by [`Cask::get_ref`]
+#[derive(Debug, Clone)]
+pub enum ValueRef {
+    /// Borrowed from the map of an immutable file
+    Mapped(MappedSlice),
+
+    /// Read into memory
+    Owned(Vec<u8>),
+}
+
+impl Deref for ValueRef {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ValueRef::Mapped(slice) => slice,
+            ValueRef::Owned(value) => value,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ValueRef {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 struct CacheEntry {
     fd: Fd,
     value_size: u32,
     offset: Offset,
-    timestamp: u64,
+    /// Milliseconds since the unix epoch from which on the entry is expired, `0` if it never
+    /// expires
+    expires_at: u64,
 }
 
 impl CacheEntry {
     pub fn data_offset(&self) -> Offset {
         Offset(self.offset.0 + Header::LEN as usize)
     }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        repr::is_expired(self.expires_at, now)
+    }
+
+    /// Size of the whole entry on disk, given the length of its key
+    pub fn entry_size(&self, key_len: usize) -> u64 {
+        Header::LEN + key_len as u64 + self.value_size as u64
+    }
 }
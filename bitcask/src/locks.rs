@@ -0,0 +1,58 @@
+//! Per-key write locks
+//!
+//! Every write to a key holds that key's lock from the moment it appends to the active file until
+//! the KeyDir reflects the write. This keeps the order of the KeyDir updates for a key in line
+//! with the order of its entries in the log, and lets conditional writes check a key and write it
+//! without anyone else getting in between. Writes to different keys rarely share a lock, so they
+//! can still be grouped into a single commit.
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{Mutex, MutexGuard},
+};
+
+/// Number of locks keys are spread across
+const STRIPES: usize = 64;
+
+#[derive(Debug)]
+pub(crate) struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+}
+
+impl KeyLocks {
+    pub fn new() -> Self {
+        KeyLocks {
+            stripes: (0..STRIPES).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    fn stripe(&self, key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % self.stripes.len()
+    }
+
+    /// Locks a single key
+    pub fn lock(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        self.stripes[self.stripe(key)]
+            .lock()
+            .expect("Unable to lock key")
+    }
+
+    /// Locks several keys at once
+    ///
+    /// Locks are always taken in the same order, so that concurrent callers can't deadlock.
+    pub fn lock_all<'key>(
+        &self,
+        keys: impl IntoIterator<Item = &'key [u8]>,
+    ) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: Vec<usize> = keys.into_iter().map(|key| self.stripe(key)).collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+
+        stripes
+            .into_iter()
+            .map(|stripe| self.stripes[stripe].lock().expect("Unable to lock key"))
+            .collect()
+    }
+}
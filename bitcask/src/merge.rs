@@ -0,0 +1,525 @@
+//! Drives the [`Compactor`] state machine against a [`Cask`]
+//!
+//! A merge reads every immutable file, copies the entries the KeyDir still points at into a
+//! single new data file and then deletes the files it read. Entries which have expired are not
+//! copied, which drops them for good. With encryption configured, entries which are not sealed
+//! with the current key are re-encrypted on the way. The merged file is ordered right
+//! after the newest file that went into it, so replaying the data files on startup gives the same
+//! result before and after the merge, even if the process dies half way through.
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+use crossbeam_channel::{bounded, Receiver};
+
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{
+    compactor::{Compactor, Input, Operation, Triggers},
+    fs::{Fd, Offset},
+    repr::{Entry, FileHeader, Header, HintHeader},
+    CacheEntry, Cask, CaskError, ClockSource, Config, Encryption, HeaderIter, Inner, System,
+};
+
+/// Upper bound on how long the background loops sleep before checking whether the data store has
+/// been dropped.
+pub(crate) const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/// Snapshot of how far a compaction has come
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionProgress {
+    /// Number of input files read to the end
+    pub files_processed: usize,
+    /// Bytes freed on disk, only known once the merged file has been committed
+    pub bytes_reclaimed: u64,
+    /// Number of live entries copied into the merged file
+    pub entries_copied: usize,
+}
+
+/// Progress counters shared between a running compaction and its [`CompactionHandle`]
+#[derive(Debug, Default)]
+pub(crate) struct Progress {
+    files_processed: AtomicUsize,
+    bytes_reclaimed: AtomicU64,
+    entries_copied: AtomicUsize,
+    cancelled: AtomicBool,
+}
+
+impl Progress {
+    fn snapshot(&self) -> CompactionProgress {
+        CompactionProgress {
+            files_processed: self.files_processed.load(Ordering::Relaxed),
+            bytes_reclaimed: self.bytes_reclaimed.load(Ordering::Relaxed),
+            entries_copied: self.entries_copied.load(Ordering::Relaxed),
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// Handle to a compaction started with [`Cask::compact`]
+pub struct CompactionHandle {
+    progress: Arc<Progress>,
+    result: Receiver<Result<CompactionProgress, CaskError>>,
+}
+
+impl CompactionHandle {
+    /// How far the compaction has come so far
+    pub fn progress(&self) -> CompactionProgress {
+        self.progress.snapshot()
+    }
+
+    /// Asks the compaction to stop
+    ///
+    /// A cancelled compaction removes the partially written merged file and leaves the data
+    /// files it was reading untouched. Cancelling has no effect once the merged file has been
+    /// committed.
+    pub fn cancel(&self) {
+        self.progress.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the compaction has finished, successfully or not
+    pub fn is_finished(&self) -> bool {
+        !self.result.is_empty()
+    }
+
+    /// Blocks until the compaction has finished and returns its final progress
+    ///
+    /// Returns [`CaskError::Cancelled`] if the compaction was cancelled before it committed.
+    pub fn wait(self) -> Result<CompactionProgress, CaskError> {
+        self.result
+            .recv()
+            .expect("Compaction job dropped without reporting a result")
+    }
+}
+
+impl<T> Cask<T>
+where
+    T: System,
+{
+    /// Merges every immutable file into a single data file
+    ///
+    /// Entries that have been overwritten, deleted or have expired are dropped, and the space they
+    /// took up on disk is reclaimed. The active file is never part of a merge.
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{Cask, test::TestFileSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    ///     let cask: Cask<TestFileSystem> = Cask::new("")?;
+    ///     cask.insert("hello", "world")?;
+    ///     cask.merge()?;
+    ///     assert_eq!(cask.get(&"hello")?, "world".as_bytes());
+    ///     # Ok(())
+    /// # }
+    /// ```
+    pub fn merge(&self) -> Result<(), CaskError> {
+        let mut compactor = Compactor::new();
+        self.run_compactor(&mut compactor, &Progress::default())
+    }
+
+    /// Starts merging every immutable file on the thread pool
+    ///
+    /// This performs the same merge as [`Cask::merge`], without blocking the caller. The returned
+    /// handle reports progress and can cancel the merge. If another merge is already running, this
+    /// one starts once it has finished.
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{Cask, test::TestFileSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    ///     let cask: Cask<TestFileSystem> = Cask::new("")?;
+    ///     cask.insert("hello", "world")?;
+    ///     let progress = cask.compact().wait()?;
+    ///     assert_eq!(progress.entries_copied, 0);
+    ///     # Ok(())
+    /// # }
+    /// ```
+    pub fn compact(&self) -> CompactionHandle {
+        let progress = Arc::new(Progress::default());
+        let (send, result) = bounded(1);
+
+        let cask = self.clone();
+        let job_progress = progress.clone();
+        self.inner.pool.execute(move || {
+            let mut compactor = Compactor::new();
+            let result = cask
+                .run_compactor(&mut compactor, &job_progress)
+                .map(|()| job_progress.snapshot());
+            // Nobody is interested in the result if the handle has been dropped
+            let _ = send.send(result);
+        });
+
+        CompactionHandle { progress, result }
+    }
+
+    #[instrument(skip(inner, config))]
+    pub(crate) fn compaction_loop(inner: Weak<Inner<T>>, config: Config) {
+        let Some(now) = inner.upgrade().map(|inner| inner.fs.instant()) else {
+            return;
+        };
+        let mut compactor = Compactor::background(Triggers::from(&config), now);
+
+        loop {
+            let Some(inner) = inner.upgrade() else {
+                info!("Data store dropped, exiting compaction loop");
+                return;
+            };
+            let cask = Cask {
+                inner,
+                config: config.clone(),
+            };
+
+            compactor.handle_timeout(cask.inner.fs.instant());
+            if let Err(err) = cask.run_compactor(&mut compactor, &Progress::default()) {
+                error!(error = %err, "Merge failed");
+            }
+            let now = cask.inner.fs.instant();
+            drop(cask);
+
+            // Time might be controlled by a test clock, so never rely on the sleep being accurate
+            if let Some(deadline) = compactor.poll_timeout() {
+                thread::sleep(deadline.saturating_duration_since(now).min(SHUTDOWN_POLL));
+            }
+        }
+    }
+
+    /// Performs operations until the compactor has nothing left to do
+    ///
+    /// Only one compactor runs at a time, any other caller blocks until it is done.
+    fn run_compactor(
+        &self,
+        compactor: &mut Compactor,
+        progress: &Progress,
+    ) -> Result<(), CaskError> {
+        let _running = self.inner.compaction.lock().unwrap();
+        self.remove_retired();
+        let mut merge = Merge::new(self, progress);
+
+        while let Some(operation) = compactor.poll_transmit() {
+            let result = if progress.is_cancelled() {
+                Err(CaskError::Cancelled)
+            } else {
+                merge.perform(operation, compactor)
+            };
+
+            if let Err(err) = result {
+                warn!(error = %err, "Aborting merge");
+                compactor.abort(self.inner.fs.instant());
+                merge.abort();
+                return Err(err);
+            }
+        }
+
+        // Snapshots closed while the merge was running left their retired files to it
+        self.remove_retired();
+        Ok(())
+    }
+}
+
+/// State of a single merge
+struct Merge<'cask, T>
+where
+    T: System,
+{
+    cask: &'cask Cask<T>,
+    progress: &'cask Progress,
+    /// Files being merged, oldest first
+    inputs: Vec<Fd>,
+    /// Index into `inputs` of the file being read
+    input: usize,
+    entries: Option<HeaderIter<'cask, T>>,
+    /// Entry read last
+    current: Option<(Vec<u8>, Header, CacheEntry)>,
+    output: Option<Fd>,
+    cursor: Offset,
+    hints: Vec<u8>,
+    /// Keys copied into the output, along with their old and new location
+    moved: Vec<(Vec<u8>, CacheEntry, CacheEntry)>,
+    /// Keys whose entry had expired, along with its location
+    expired: Vec<(Vec<u8>, CacheEntry)>,
+}
+
+impl<'cask, T> Merge<'cask, T>
+where
+    T: System,
+{
+    fn new(cask: &'cask Cask<T>, progress: &'cask Progress) -> Self {
+        Merge {
+            cask,
+            progress,
+            inputs: Vec::new(),
+            input: 0,
+            entries: None,
+            current: None,
+            output: None,
+            cursor: Offset(0),
+            hints: Vec::new(),
+            moved: Vec::new(),
+            expired: Vec::new(),
+        }
+    }
+
+    #[instrument(skip(self, compactor))]
+    fn perform(
+        &mut self,
+        operation: Operation,
+        compactor: &mut Compactor,
+    ) -> Result<(), CaskError> {
+        match operation {
+            Operation::CheckFragmentation => {
+                let active = self.cask.inner.fs.active_fd();
+                let retired = self.cask.inner.snapshots.lock().unwrap().retired().to_vec();
+                let stats = self.cask.inner.stats.lock().unwrap();
+
+                let mut dead_bytes = 0;
+                let mut dead_ratio: f64 = 0.0;
+                for fd in self.cask.inner.fs.files() {
+                    if fd == active || retired.contains(&fd) {
+                        continue;
+                    }
+                    let file = stats.get(fd);
+                    dead_bytes += file.dead_bytes;
+                    dead_ratio = dead_ratio.max(file.dead_ratio());
+                }
+
+                debug!(dead_bytes, dead_ratio, "Checked fragmentation");
+                compactor.handle_input(Input::Fragmentation {
+                    dead_bytes,
+                    dead_ratio,
+                    now: self.cask.inner.fs.instant(),
+                });
+            }
+            Operation::CheckFile => {
+                let fs = &self.cask.inner.fs;
+                let active = fs.active_fd();
+                let retired = self.cask.inner.snapshots.lock().unwrap().retired().to_vec();
+                self.inputs = fs
+                    .files()
+                    .into_iter()
+                    .filter(|fd| *fd != active && !retired.contains(fd))
+                    .collect();
+                info!(inputs = ?self.inputs, retired = ?retired, "Starting merge");
+
+                // Retired files are older than any input, and values in them might still be
+                // shadowed by tombstones in the inputs
+                compactor.handle_input(Input::Files {
+                    keep_tombstones: !retired.is_empty(),
+                });
+            }
+            Operation::NextEntry => match self.next_entry()? {
+                Some((key, header, cache_entry)) => {
+                    compactor.handle_input(Input::Entry {
+                        tombstone: header.is_tombstone(),
+                    });
+                    self.current = Some((key, header, cache_entry));
+                }
+                None => compactor.handle_input(Input::End(self.cask.inner.fs.instant())),
+            },
+            Operation::CheckKeydir => {
+                let (key, _, cache_entry) = self.current();
+                let keydir = self.cask.inner.keydir.read().unwrap();
+                if keydir.get(key) != Some(*cache_entry) {
+                    compactor.handle_input(Input::NotMatchkeydir);
+                } else if cache_entry.is_expired(self.cask.now()) {
+                    // Nothing older than the entry survives the merge, so the key stays gone
+                    // without writing a tombstone
+                    self.expired.push((key.clone(), *cache_entry));
+                    compactor.handle_input(Input::NotMatchkeydir);
+                } else {
+                    compactor.handle_input(Input::MatchKeydir);
+                }
+            }
+            Operation::AddImmutable => self.copy_current()?,
+            Operation::AddHint => {
+                let (key, header, _) = self.current.as_ref().expect("Hint without an entry");
+                let (_, _, new_entry) = self.moved.last().expect("Hint added before copy");
+                HintHeader::append(&mut self.hints, header, key, new_entry.offset.0 as u64);
+            }
+            Operation::Ignore => self.current = None,
+            Operation::Commit => self.commit()?,
+        }
+
+        Ok(())
+    }
+
+    fn current(&self) -> &(Vec<u8>, Header, CacheEntry) {
+        self.current
+            .as_ref()
+            .expect("Operation issued without an entry")
+    }
+
+    /// Reads the next entry out of the input files
+    fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Header, CacheEntry)>, CaskError> {
+        loop {
+            if let Some(entries) = self.entries.as_mut() {
+                if let Some(entry) = entries.next() {
+                    return entry.map(Some);
+                }
+
+                // Input files are immutable, so an incomplete entry is not the result of a crash
+                if let Some(offset) = entries.torn_tail() {
+                    return Err(CaskError::Corruption {
+                        fd: self.inputs[self.input],
+                        offset,
+                    });
+                }
+
+                self.entries = None;
+                self.input += 1;
+                self.progress
+                    .files_processed
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+
+            let Some(fd) = self.inputs.get(self.input) else {
+                return Ok(None);
+            };
+            self.entries = Some(HeaderIter::new(&self.cask.inner.fs, *fd)?);
+        }
+    }
+
+    /// Copies the current entry verbatim into the output file
+    fn copy_current(&mut self) -> Result<(), CaskError> {
+        let fs = &self.cask.inner.fs;
+        let output = match self.output {
+            Some(output) => output,
+            None => {
+                let newest = *self.inputs.last().expect("Copying without input files");
+                let output = fs.new_merge(newest)?;
+                self.output = Some(output);
+                self.cursor = Offset(FileHeader::LEN as usize);
+                output
+            }
+        };
+
+        let (key, header, cache_entry) = self.current.as_mut().expect("Copy without an entry");
+
+        let mut buf = vec![0u8; header.entry_size()];
+        fs.get_chunk_fd(cache_entry.offset, &mut buf, cache_entry.fd)?;
+
+        // Entries written in plaintext or sealed with an older key get sealed with the current
+        // key. The header is updated as well, so that the hint matches what was written.
+        let data = &buf[Header::LEN as usize..];
+        let stale =
+            |encryption: &&Encryption| !header.is_encrypted() || !encryption.is_current(data);
+        if let Some(encryption) = fs.encryption().filter(stale) {
+            let plaintext = if header.is_encrypted() {
+                fs.decrypt(data)?
+            } else {
+                data.to_vec()
+            };
+            let (plain_key, value) = plaintext.split_at(header.key_size as usize);
+            (*header, buf) =
+                Entry::from_parts(*header, plain_key, value).serialize(Some(encryption));
+        }
+
+        // The batch has been committed, and its commit record is not copied over
+        if header.in_batch() {
+            let mut header = *header;
+            header.flags &= !Header::IN_BATCH;
+            header.crc = header.checksum(&[&buf[Header::LEN as usize..]]);
+            buf[..Header::LEN as usize].copy_from_slice(header.serialize());
+        }
+        fs.write_all_at(output, &buf, self.cursor)?;
+
+        let new_entry = CacheEntry {
+            fd: output,
+            offset: self.cursor,
+            value_size: header.value_size,
+            ..*cache_entry
+        };
+        self.cursor = Offset(self.cursor.0 + buf.len());
+        self.moved.push((key.clone(), *cache_entry, new_entry));
+        self.progress.entries_copied.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Makes the merged file durable, points the KeyDir at it and deletes the input files
+    #[instrument(skip(self))]
+    fn commit(&mut self) -> Result<(), CaskError> {
+        let fs = &self.cask.inner.fs;
+
+        if let Some(output) = self.output {
+            fs.write_hint(output, &self.hints)?;
+            fs.commit_merge(output)?;
+
+            // Keys written or removed while the merge was running have moved on, leave them be
+            let mut keydir = self.cask.inner.keydir.write().unwrap();
+            let mut stats = self.cask.inner.stats.lock().unwrap();
+            for (key, old_entry, new_entry) in self.moved.drain(..) {
+                let size = new_entry.entry_size(key.len());
+                if keydir.get(&key) == Some(old_entry) {
+                    keydir.insert(key, new_entry);
+                    stats.add_live(output, size);
+                } else {
+                    stats.add_dead(output, size);
+                }
+            }
+        }
+
+        // Keys written since their expired entry was read have moved on, leave them be
+        {
+            let mut keydir = self.cask.inner.keydir.write().unwrap();
+            for (key, old_entry) in self.expired.drain(..) {
+                if keydir.get(&key) == Some(old_entry) {
+                    keydir.remove(&key);
+                }
+            }
+        }
+
+        // Open snapshots might still read from the inputs, they get deleted once the last one
+        // has been dropped
+        let mut reclaimed = 0;
+        if self
+            .cask
+            .inner
+            .snapshots
+            .lock()
+            .unwrap()
+            .retire(&self.inputs)
+        {
+            info!(inputs = ?self.inputs, "Retiring inputs for open snapshots");
+            // Retired files are only kept for reads, a merge has nothing left to reclaim in them
+            let mut stats = self.cask.inner.stats.lock().unwrap();
+            for input in self.inputs.drain(..) {
+                stats.remove(input);
+            }
+        }
+
+        // Nothing references the inputs anymore. Delete the oldest files first, so that a crash
+        // in between never leaves a value around without the tombstone that shadowed it.
+        for input in self.inputs.drain(..) {
+            reclaimed += fs.file_size(input)?;
+            fs.remove(input)?;
+            self.cask.inner.stats.lock().unwrap().remove(input);
+            self.cask.inner.cache.remove_file(input);
+        }
+
+        let reclaimed = reclaimed.saturating_sub(self.cursor.0 as u64);
+        self.progress
+            .bytes_reclaimed
+            .store(reclaimed, Ordering::Relaxed);
+
+        info!(output = ?self.output, reclaimed, "Finished merge");
+        self.output = None;
+        Ok(())
+    }
+
+    /// Cleans up the output of a failed merge
+    fn abort(&mut self) {
+        if let Some(output) = self.output.take() {
+            if let Err(err) = self.cask.inner.fs.remove(output) {
+                error!(error = %err, "Unable to remove output of failed merge");
+            }
+        }
+    }
+}
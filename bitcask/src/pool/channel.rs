@@ -2,7 +2,8 @@ use crossbeam_channel::unbounded;
 
 #[derive(Debug, Clone)]
 pub(super) struct Sender {
-    send: crossbeam_channel::Sender<()>,
+    // Never sent on, the pool relies on the drop of the last sender to signal shutdown.
+    _send: crossbeam_channel::Sender<()>,
 }
 
 #[derive(Debug, Clone)]
@@ -12,5 +13,5 @@ pub(super) struct Receiver {
 
 pub(super) fn channel() -> (Sender, Receiver) {
     let (send, recv) = unbounded();
-    (Sender { send }, Receiver { recv })
+    (Sender { _send: send }, Receiver { recv })
 }
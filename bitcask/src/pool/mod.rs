@@ -169,7 +169,7 @@ impl Pool {
         // Wake up any idle threads to let them know that we're shutting down.
         // When all existing threads have finished their run loops, we drop the send half, which
         // results in an err
-        if let Err(_) = self.shutdown_rx.recv.recv() {
+        if self.shutdown_rx.recv.recv().is_err() {
             debug!("All threads have exited core loop");
             for (_id, worker) in workers {
                 let _ = worker.join();
@@ -185,7 +185,7 @@ impl Pool {
         id: usize,
         shutdown_tx: channel::Sender,
     ) -> io::Result<thread::JoinHandle<()>> {
-        let builder = thread::Builder::new();
+        let builder = thread::Builder::new().name(format!("bitcask-pool-{id}"));
         let pool_handle = self.clone();
 
         builder.spawn(move || {
@@ -220,7 +220,7 @@ impl Inner {
                 // drop the mutex guard as we've obtained a job from the queue
                 drop(shared);
                 // todo: Use a channel to send result
-                let _result = job();
+                job();
 
                 shared = self.shared.lock().unwrap();
             }
@@ -301,6 +301,6 @@ mod unit_tests {
         }
 
         info!("Waiting for work");
-        assert_eq!(recv.iter().take(n_jobs).fold(0, |acc, i| acc + i), 40);
+        assert_eq!(recv.iter().take(n_jobs).sum::<i32>(), 40);
     }
 }
@@ -1,11 +1,11 @@
 #[cfg(loom)]
 pub(crate) use loom::{
     sync::{atomic::AtomicUsize, Arc, Condvar, Mutex},
-    thread::{self, JoinHandle},
+    thread,
 };
 
 #[cfg(not(loom))]
 pub(crate) use std::{
     sync::{atomic::AtomicUsize, Arc, Condvar, Mutex},
-    thread::{self, JoinHandle},
+    thread,
 };
@@ -1,21 +1,30 @@
 use std::{
     backtrace::Backtrace,
     mem,
-    time::{SystemTime, SystemTimeError},
+    time::{Duration, SystemTime, SystemTimeError},
 };
 
 use bytemuck::{bytes_of, Pod, Zeroable};
 
+use crate::{ClockSource, Encryption};
+
 /// Database entry header
 ///
 /// We want to ensure the struct is packed for cleaner de/serialization
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 #[repr(C, packed)]
 pub(crate) struct Header {
+    /// CRC32 over the rest of the header, the key and the value
+    pub crc: u32,
     // todo: we're using unix timestamps, so we should be able to pack tombstone information into
     // the higher order bits of a u64
-    pub tombstone: u8,
+    /// Combination of the `IS_DELETED`, `IN_BATCH`, `BATCH_COMMIT`, compression and encryption
+    /// flags
+    pub flags: u8,
     pub timestamp: u64,
+    /// Milliseconds since the unix epoch from which on the entry is expired, `0` if it never
+    /// expires
+    pub expires_at: u64,
     pub key_size: u16,
     pub value_size: u32,
 }
@@ -23,6 +32,17 @@ pub(crate) struct Header {
 impl Header {
     pub const IS_DELETED: u8 = 1;
     pub const NOT_DELETED: u8 = 0;
+    /// The entry is part of a write batch, and only takes effect once the batch commit record
+    /// following it has been written
+    pub const IN_BATCH: u8 = 1 << 1;
+    /// The entry closes a write batch. It has no key, its value is the number of entries in the
+    /// batch as a little endian u32.
+    pub const BATCH_COMMIT: u8 = 1 << 2;
+    /// The value is LZ4 compressed, prefixed with its uncompressed size
+    pub const LZ4: u8 = 1 << 3;
+    /// The key and value are sealed together, see [`Encryption`]. The key size is that of the
+    /// plaintext key, and the value size covers the rest of the sealed data.
+    pub const ENCRYPTED: u8 = 1 << 4;
     pub const LEN: u64 = mem::size_of::<Header>() as u64;
 
     /// The size of the data field in this entry
@@ -37,11 +57,165 @@ impl Header {
         Header::LEN as usize + self.data_size()
     }
 
+    pub fn is_tombstone(&self) -> bool {
+        self.flags & Header::IS_DELETED != 0
+    }
+
+    pub fn in_batch(&self) -> bool {
+        self.flags & Header::IN_BATCH != 0
+    }
+
+    pub fn is_batch_commit(&self) -> bool {
+        self.flags & Header::BATCH_COMMIT != 0
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & Header::ENCRYPTED != 0
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        is_expired(self.expires_at, now)
+    }
+
+    /// Computes the checksum of this header along with the data of its entry
+    ///
+    /// The `crc` field itself is not part of the checksum.
+    pub fn checksum(&self, data: &[&[u8]]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&bytes_of(self)[mem::size_of::<u32>()..]);
+        for chunk in data {
+            hasher.update(chunk);
+        }
+        hasher.finalize()
+    }
+
+    /// Checks the stored checksum against the |key|value| data of this entry
+    pub fn verify(&self, data: &[u8]) -> bool {
+        // Copy out of the packed struct before comparing
+        let crc = self.crc;
+        crc == self.checksum(&[data])
+    }
+
     pub fn serialize(&self) -> &[u8] {
         bytes_of(self)
     }
 }
 
+/// Header at the start of every data file
+///
+/// Identifies the file as a data file along with the version of the format its entries are
+/// written in. Files without it, like those written before it was introduced, are refused rather
+/// than misread.
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+pub(crate) struct FileHeader {
+    pub magic: [u8; 4],
+    pub version: u16,
+}
+
+impl FileHeader {
+    pub const MAGIC: [u8; 4] = *b"CASK";
+    /// Version of the entry format, bumped whenever it changes
+    pub const VERSION: u16 = 1;
+    pub const LEN: u64 = mem::size_of::<FileHeader>() as u64;
+
+    /// File header of data files written by this version
+    pub fn current() -> Self {
+        FileHeader {
+            magic: FileHeader::MAGIC,
+            version: FileHeader::VERSION,
+        }
+    }
+
+    /// Reads the file header at the start of `buf`, `None` if there is none
+    pub fn parse(buf: &[u8]) -> Option<FileHeader> {
+        let header: FileHeader =
+            *bytemuck::try_from_bytes(buf.get(..FileHeader::LEN as usize)?).ok()?;
+        (header.magic == FileHeader::MAGIC).then_some(header)
+    }
+
+    pub fn serialize(&self) -> &[u8] {
+        bytes_of(self)
+    }
+}
+
+/// Header of an entry in a hint file
+///
+/// Hint files mirror the entries of an immutable data file without their values, which allows
+/// rebuilding the KeyDir without reading every value. Each entry is encoded as |HintHeader|key|.
+/// The data file an entry refers to is implied by the hint file it lives in.
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+pub(crate) struct HintHeader {
+    /// CRC32 over the rest of the header and the key
+    pub crc: u32,
+    pub tombstone: u8,
+    pub timestamp: u64,
+    pub expires_at: u64,
+    pub key_size: u16,
+    pub value_size: u32,
+    /// Offset of the entry in the data file
+    pub offset: u64,
+}
+
+impl HintHeader {
+    pub const LEN: u64 = mem::size_of::<HintHeader>() as u64;
+
+    /// Appends the hint for a data file entry to `buf`
+    pub fn append(buf: &mut Vec<u8>, header: &Header, key: &[u8], offset: u64) {
+        let mut hint = HintHeader {
+            crc: 0,
+            tombstone: header.flags & Header::IS_DELETED,
+            timestamp: header.timestamp,
+            expires_at: header.expires_at,
+            key_size: header.key_size,
+            value_size: header.value_size,
+            offset,
+        };
+        hint.crc = hint.checksum(key);
+
+        buf.extend_from_slice(bytes_of(&hint));
+        buf.extend_from_slice(key);
+    }
+
+    /// Decodes every entry of a hint file
+    ///
+    /// Returns `None` if the file is truncated or any of the entries fail their checksum, in which
+    /// case the data file has to be read instead.
+    pub fn parse(mut buf: &[u8]) -> Option<Vec<(HintHeader, &[u8])>> {
+        let mut hints = Vec::new();
+
+        while !buf.is_empty() {
+            let header_buf = buf.get(..HintHeader::LEN as usize)?;
+            let hint: HintHeader = *bytemuck::try_from_bytes(header_buf).ok()?;
+
+            let entry_end = HintHeader::LEN as usize + hint.key_size as usize;
+            let key = buf.get(HintHeader::LEN as usize..entry_end)?;
+
+            let crc = hint.crc;
+            if crc != hint.checksum(key) {
+                return None;
+            }
+
+            hints.push((hint, key));
+            buf = &buf[entry_end..];
+        }
+
+        Some(hints)
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.tombstone == Header::IS_DELETED
+    }
+
+    fn checksum(&self, key: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&bytes_of(self)[mem::size_of::<u32>()..]);
+        hasher.update(key);
+        hasher.finalize()
+    }
+}
+
 /// Represents an entry in a data file.
 #[derive(Debug)]
 pub struct Entry<'input> {
@@ -51,10 +225,15 @@ pub struct Entry<'input> {
 }
 
 impl<'input> Entry<'input> {
-    pub fn new_encoded<K, V>(key: &'input K, value: &'input V) -> Result<Entry<'input>, EntryError>
+    pub fn new_encoded<K, V, C>(
+        key: &'input K,
+        value: &'input V,
+        clock: &C,
+    ) -> Result<Entry<'input>, EntryError>
     where
         K: AsRef<[u8]>,
         V: AsRef<[u8]>,
+        C: ClockSource,
     {
         let key = key.as_ref();
         let val = value.as_ref();
@@ -65,16 +244,15 @@ impl<'input> Entry<'input> {
         debug_assert!((key_len as u16) < u16::MAX);
         debug_assert!((val_len as u32) < u32::MAX);
 
-        // TODO: This needs to be made deterministic for tests
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_secs();
+        let timestamp = unix_timestamp(clock)?;
 
         let header = Header {
-            tombstone: Header::NOT_DELETED,
+            crc: 0,
+            flags: Header::NOT_DELETED,
             key_size: key_len as u16,
             value_size: val_len as u32,
             timestamp,
+            expires_at: 0,
         };
 
         Ok(Entry {
@@ -85,41 +263,93 @@ impl<'input> Entry<'input> {
     }
 
     /// Creates an empty tombstone entry for deleted values
-    pub fn new_empty<K>(key: &'input K) -> Entry<'input>
+    pub fn new_empty<K, C>(key: &'input K, clock: &C) -> Result<Entry<'input>, EntryError>
     where
         K: AsRef<[u8]>,
+        C: ClockSource,
     {
         let key = key.as_ref();
         debug_assert!(key.len() < u16::MAX.into());
-        Entry {
+        Ok(Entry {
             header: Header {
-                tombstone: Header::IS_DELETED,
-                timestamp: 0,
+                crc: 0,
+                flags: Header::IS_DELETED,
+                timestamp: unix_timestamp(clock)?,
+                expires_at: 0,
                 key_size: key.len() as u16,
                 value_size: 0,
             },
             key,
             value: None,
+        })
+    }
+
+    /// Reassembles an entry read back from a data file, with its key and value in plaintext
+    pub fn from_parts(header: Header, key: &'input [u8], value: &'input [u8]) -> Self {
+        let mut header = header;
+        header.flags &= !Header::ENCRYPTED;
+        header.value_size = value.len() as u32;
+        Entry {
+            header,
+            key,
+            value: Some(value),
         }
     }
 
+    /// Serializes the entry, sealing its key and value if `encryption` is given
+    ///
+    /// Returns the header as written along with the entry.
     // TODO: Allocating a whole vector for the entry is wasteful. We should be able to write the
     // whole structure to the file somehow.
-    pub fn serialize(&self) -> Vec<u8> {
-        [
-            self.header.serialize(),
-            self.key,
-            self.value.unwrap_or_else(|| &[]),
-        ]
-        .concat()
+    pub fn serialize(&self, encryption: Option<&Encryption>) -> (Header, Vec<u8>) {
+        let value = self.value.unwrap_or(&[]);
+        let mut header = self.header;
+
+        let Some(encryption) = encryption else {
+            header.crc = header.checksum(&[self.key, value]);
+            return (header, [header.serialize(), self.key, value].concat());
+        };
+
+        let sealed = encryption.seal(&[self.key, value]);
+        header.flags |= Header::ENCRYPTED;
+        header.value_size = (sealed.len() - self.key.len()) as u32;
+        header.crc = header.checksum(&[&sealed]);
+
+        (header, [header.serialize(), &sealed].concat())
     }
 
     pub fn len(&self) -> usize {
         (Header::LEN + self.header.key_size as u64 + self.header.value_size as u64) as usize
     }
 
-    pub fn is_tombstone(&self) -> bool {
-        self.header.tombstone == Header::IS_DELETED
+    /// Marks the entry as part of a write batch
+    pub fn batched(mut self) -> Self {
+        self.header.flags |= Header::IN_BATCH;
+        self
+    }
+
+    /// Adds `flags` describing the encoding of the value
+    pub fn flagged(mut self, flags: u8) -> Self {
+        self.header.flags |= flags;
+        self
+    }
+
+    /// Overrides the time the entry was written at, in seconds since the unix epoch
+    pub fn stamped(mut self, timestamp: u64) -> Self {
+        self.header.timestamp = timestamp;
+        self
+    }
+
+    /// Makes the entry expire once `ttl` has passed
+    pub fn expiring<C: ClockSource>(
+        mut self,
+        ttl: Duration,
+        clock: &C,
+    ) -> Result<Self, EntryError> {
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        // Zero means the entry never expires, so expire entries with a zero ttl a moment later
+        self.header.expires_at = unix_millis(clock)?.saturating_add(ttl).max(1);
+        Ok(self)
     }
 
     pub fn key(&self) -> &[u8] {
@@ -127,6 +357,49 @@ impl<'input> Entry<'input> {
     }
 }
 
+/// Serializes the record committing a write batch of `count` entries written at `timestamp`
+pub fn batch_commit(count: u32, timestamp: u64) -> Vec<u8> {
+    let value = count.to_le_bytes();
+    let mut header = Header {
+        crc: 0,
+        flags: Header::BATCH_COMMIT,
+        timestamp,
+        expires_at: 0,
+        key_size: 0,
+        value_size: value.len() as u32,
+    };
+    header.crc = header.checksum(&[&value]);
+
+    [header.serialize(), &value].concat()
+}
+
+/// Whether an entry expiring at `expires_at` has expired at `now`, both in milliseconds since the
+/// unix epoch
+pub fn is_expired(expires_at: u64, now: u64) -> bool {
+    expires_at != 0 && now >= expires_at
+}
+
+/// Seconds since the unix epoch according to `clock`
+pub fn unix_timestamp<C: ClockSource>(clock: &C) -> Result<u64, EntryError> {
+    timestamp_of(clock.system_time())
+}
+
+/// Milliseconds since the unix epoch according to `clock`
+pub fn unix_millis<C: ClockSource>(clock: &C) -> Result<u64, EntryError> {
+    millis_of(clock.system_time())
+}
+
+/// Seconds since the unix epoch at `time`, the resolution of entry timestamps
+pub fn timestamp_of(time: SystemTime) -> Result<u64, EntryError> {
+    Ok(time.duration_since(SystemTime::UNIX_EPOCH)?.as_secs())
+}
+
+/// Milliseconds since the unix epoch at `time`, the resolution of expiry times
+pub fn millis_of(time: SystemTime) -> Result<u64, EntryError> {
+    let millis = time.duration_since(SystemTime::UNIX_EPOCH)?.as_millis();
+    Ok(u64::try_from(millis).unwrap_or(u64::MAX))
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum EntryError {
     #[error("Error converting timestamp: {source}")]
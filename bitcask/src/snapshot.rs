@@ -0,0 +1,317 @@
+//! Consistent read-only views of a [`Cask`]
+//!
+//! A snapshot owns a copy of the KeyDir taken at the time it was created, so writes made
+//! afterwards are invisible to it. The entries it points at live in data files that a merge would
+//! otherwise delete. While any snapshot is open, merges leave their input files in place and
+//! retire them instead. Retired files are deleted once the last snapshot is dropped. Retired files
+//! still hold entries in their original order, so replaying them after a crash only
+//! re-establishes what the merged file contains anyway. Later merges leave retired files out, and
+//! keep the tombstones which still shadow values in them.
+use std::{
+    mem,
+    ops::RangeBounds,
+    time::{Duration, SystemTime},
+};
+
+use tracing::{error, info, instrument};
+
+use crate::{
+    fs::Fd,
+    iter::{self, Iter, Keys, Source},
+    keydir::KeyDir,
+    repr, Cask, CaskError, Config, System,
+};
+
+/// Read-only view of a [`Cask`] as it was when [`Cask::snapshot`] was called, or at the time
+/// passed to [`Cask::open_as_of`]
+pub struct Snapshot<T>
+where
+    T: System,
+{
+    pub(crate) cask: Cask<T>,
+    pub(crate) keydir: KeyDir,
+    /// Time the snapshot was taken at, entries that expire later are still visible
+    pub(crate) now: u64,
+}
+
+/// Open snapshots and the data files kept around for them
+#[derive(Debug, Default)]
+pub(crate) struct Snapshots {
+    open: usize,
+    /// Input files of merges which finished while snapshots were open, oldest first
+    retired: Vec<Fd>,
+}
+
+impl Snapshots {
+    /// Keeps `files` around for the open snapshots, if there are any
+    ///
+    /// Returns whether the files were retired, otherwise the caller can delete them right away.
+    pub fn retire(&mut self, files: &[Fd]) -> bool {
+        if self.open == 0 {
+            return false;
+        }
+
+        for fd in files {
+            if !self.retired.contains(fd) {
+                self.retired.push(*fd);
+            }
+        }
+        true
+    }
+
+    /// Files kept around for the open snapshots, which merges leave alone
+    pub fn retired(&self) -> &[Fd] {
+        &self.retired
+    }
+}
+
+impl<T> Cask<T>
+where
+    T: System,
+{
+    /// Creates a read-only view of the data store as it is right now
+    ///
+    /// The snapshot copies the KeyDir, so taking one costs memory proportional to the number of
+    /// keys. Merges keep running while snapshots are open, but the disk space they free is only
+    /// reclaimed once the last snapshot has been dropped.
+    ///
+    /// ```rust
+    /// # use std::error::Error;
+    /// # use bitcask::{Cask, test::TestFileSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    ///     let cask: Cask<TestFileSystem> = Cask::new("")?;
+    ///     cask.insert("hello", "world")?;
+    ///     let snapshot = cask.snapshot();
+    ///     cask.insert("hello", "there")?;
+    ///     assert_eq!(snapshot.get(&"hello")?, "world".as_bytes());
+    ///     # Ok(())
+    /// # }
+    /// ```
+    pub fn snapshot(&self) -> Snapshot<T> {
+        let keydir = self.inner.keydir.read().unwrap();
+        // Registering the snapshot before releasing the KeyDir lock guarantees that a merge
+        // either sees it, or has already pointed the KeyDir away from its input files
+        self.inner.snapshots.lock().unwrap().open += 1;
+
+        Snapshot {
+            cask: self.clone(),
+            keydir: keydir.clone(),
+            now: self.now(),
+        }
+    }
+
+    /// Deletes the retired files once no snapshot needs them anymore
+    ///
+    /// Callers hold the compaction lock, so that no merge is reading the files.
+    pub(crate) fn remove_retired(&self) {
+        let retired = {
+            let mut snapshots = self.inner.snapshots.lock().unwrap();
+            if snapshots.open > 0 {
+                return;
+            }
+            mem::take(&mut snapshots.retired)
+        };
+
+        // Oldest first, just like a merge deletes its inputs
+        for (i, fd) in retired.iter().enumerate() {
+            if let Err(err) = self.inner.fs.remove(*fd) {
+                error!(fd = ?fd, error = %err, "Unable to remove retired file");
+                // Try again after the next merge
+                let mut snapshots = self.inner.snapshots.lock().unwrap();
+                snapshots.retired.splice(0..0, retired[i..].iter().copied());
+                return;
+            }
+            self.inner.stats.lock().unwrap().remove(*fd);
+            self.inner.cache.remove_file(*fd);
+        }
+    }
+}
+
+impl<T> Cask<T>
+where
+    T: System,
+{
+    /// Opens the data store in `path` read-only, as it was at `as_of`
+    ///
+    /// Entries and tombstones written after `as_of` are ignored, and expiry is judged at that
+    /// time. History only reaches back as far as the last merge, which drops superseded entries.
+    /// Entry timestamps have a resolution of one second, everything written during the second of
+    /// `as_of` is included. Use [`Snapshot::export`] to turn the result into a writable data
+    /// store.
+    ///
+    /// The data files are never modified, which makes this safe to point at the directory of a
+    /// data store that is in use, or at a checkpoint. Unlike opening the data store for writing,
+    /// leftovers of interrupted merges are not removed, an incomplete entry at the end of the
+    /// active file is skipped rather than truncated, and missing hint files are not recreated.
+    /// No background threads are started either, not even for
+    /// [`SyncPolicy::Interval`](crate::SyncPolicy::Interval).
+    #[instrument]
+    pub fn open_as_of(
+        path: &str,
+        config: Config,
+        as_of: SystemTime,
+    ) -> Result<Snapshot<T>, CaskError> {
+        let fs_impl = T::init_read_only(path)?;
+
+        Cask::open_as_of_with_fs_impl(config, fs_impl, as_of)
+    }
+
+    #[instrument(skip(fs_impl))]
+    pub fn open_as_of_with_fs_impl(
+        config: Config,
+        fs_impl: T,
+        as_of: SystemTime,
+    ) -> Result<Snapshot<T>, CaskError> {
+        let kind = config.keydir;
+        let cask = Cask::open(config, fs_impl, Some(as_of))?;
+
+        // The data store only serves reads for the snapshot, which owns the KeyDir
+        let keydir = mem::replace(&mut *cask.inner.keydir.write().unwrap(), KeyDir::new(kind));
+        cask.inner.snapshots.lock().unwrap().open += 1;
+
+        Ok(Snapshot {
+            cask,
+            keydir,
+            now: repr::millis_of(as_of)?,
+        })
+    }
+}
+
+impl<T> Snapshot<T>
+where
+    T: System,
+{
+    /// Copies every entry of the snapshot into `target`
+    ///
+    /// Entries which expire keep the time they had left when the snapshot was taken. Existing
+    /// keys of `target` are overwritten, others are left alone.
+    ///
+    /// ```rust
+    /// # use std::{error::Error, time::Duration};
+    /// # use bitcask::{Cask, ClockSource, FileSystem, test::TestFileSystem};
+    /// # fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    ///     let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    ///     let cask = Cask::new_with_fs_impl("", Default::default(), test_fs.clone())?;
+    ///     cask.insert("config", "good")?;
+    ///     let before_deploy = test_fs.system_time();
+    ///
+    ///     test_fs.advance(Duration::from_secs(60));
+    ///     cask.insert("config", "bad")?;
+    ///
+    ///     let as_of = Cask::open_as_of_with_fs_impl(Default::default(), test_fs, before_deploy)?;
+    ///     let restored: Cask<TestFileSystem> = Cask::new("")?;
+    ///     as_of.export(&restored)?;
+    ///     assert_eq!(restored.get(&"config")?, "good".as_bytes());
+    ///     # Ok(())
+    /// # }
+    /// ```
+    #[instrument(skip(self, target))]
+    pub fn export<U>(&self, target: &Cask<U>) -> Result<(), CaskError>
+    where
+        U: System,
+    {
+        let keys = self.keydir.keys(self.now);
+        for key in &keys {
+            let Some(cache_entry) = self.keydir.get_live(key, self.now) else {
+                continue;
+            };
+            let value = self.cask.read_value(&cache_entry)?;
+            match cache_entry.expires_at {
+                0 => target.insert(key, value)?,
+                expires_at => {
+                    let ttl = Duration::from_millis(expires_at - self.now);
+                    target.insert_with_ttl(key, value, ttl)?
+                }
+            }
+        }
+        info!(keys = keys.len(), "Exported snapshot");
+
+        Ok(())
+    }
+
+    /// Gets an entry as it was when the snapshot was taken
+    pub fn get<K>(&self, key: &K) -> Result<Vec<u8>, CaskError>
+    where
+        K: AsRef<[u8]>,
+    {
+        let Some(cache_entry) = self.keydir.get_live(key.as_ref(), self.now) else {
+            return Err(CaskError::NotFound);
+        };
+
+        self.cask.read_value(&cache_entry)
+    }
+
+    /// Whether `key` existed when the snapshot was taken
+    pub fn contains_key<K>(&self, key: &K) -> bool
+    where
+        K: AsRef<[u8]>,
+    {
+        self.keydir.get_live(key.as_ref(), self.now).is_some()
+    }
+
+    /// Number of keys in the snapshot
+    pub fn len(&self) -> usize {
+        self.keydir.len(self.now)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every key in the snapshot, in no particular order
+    pub fn keys(&self) -> Keys {
+        Keys::new(self.keydir.keys(self.now))
+    }
+
+    /// Every key in the snapshot along with its value, in no particular order
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter::new(Source::Snapshot(self), self.keys())
+    }
+
+    /// Every key within `range` along with its value, in ascending key order
+    ///
+    /// See [`Cask::range`].
+    pub fn range<K, R>(&self, range: R) -> Iter<'_, T>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let (start, end) = iter::range_bounds(range);
+        let keys = Keys::new(self.keydir.range(start, end, self.now));
+        Iter::new(Source::Snapshot(self), keys)
+    }
+
+    /// Every key starting with `prefix` along with its value, in ascending key order
+    pub fn scan_prefix<P>(&self, prefix: P) -> Iter<'_, T>
+    where
+        P: AsRef<[u8]>,
+    {
+        let (start, end) = iter::prefix_bounds(prefix.as_ref());
+        let keys = Keys::new(self.keydir.range(start, end, self.now));
+        Iter::new(Source::Snapshot(self), keys)
+    }
+}
+
+impl<T> Drop for Snapshot<T>
+where
+    T: System,
+{
+    #[instrument(skip(self))]
+    fn drop(&mut self) {
+        let inner = &self.cask.inner;
+        {
+            let mut snapshots = inner.snapshots.lock().unwrap();
+            snapshots.open -= 1;
+            if snapshots.open > 0 || snapshots.retired.is_empty() {
+                return;
+            }
+        }
+
+        // Every merge deletes the retired files once it is done, so leave them to the running one
+        let Ok(_running) = inner.compaction.try_lock() else {
+            info!("Leaving retired files to the running merge");
+            return;
+        };
+        self.cask.remove_retired();
+    }
+}
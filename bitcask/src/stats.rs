@@ -0,0 +1,64 @@
+//! Book keeping of live and dead bytes per data file
+//!
+//! Every entry starts out live. Once it is overwritten or deleted, the bytes it takes up become
+//! dead and can be reclaimed by a merge. Tombstones are dead from the moment they are written, as
+//! they only exist to shadow older entries.
+use std::collections::HashMap;
+
+use crate::fs::Fd;
+
+/// Live and dead bytes of a single data file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileStats {
+    /// Bytes taken up by entries the KeyDir points at
+    pub live_bytes: u64,
+    /// Bytes taken up by overwritten or deleted entries and tombstones
+    pub dead_bytes: u64,
+}
+
+impl FileStats {
+    /// Fraction of the file that would be reclaimed by a merge
+    pub fn dead_ratio(&self) -> f64 {
+        let total = self.live_bytes + self.dead_bytes;
+        if total == 0 {
+            return 0.0;
+        }
+        self.dead_bytes as f64 / total as f64
+    }
+}
+
+/// Tracks [`FileStats`] for every data file
+#[derive(Debug, Default)]
+pub(crate) struct Accounting {
+    files: HashMap<Fd, FileStats>,
+}
+
+impl Accounting {
+    pub fn add_live(&mut self, fd: Fd, size: u64) {
+        self.files.entry(fd).or_default().live_bytes += size;
+    }
+
+    pub fn add_dead(&mut self, fd: Fd, size: u64) {
+        self.files.entry(fd).or_default().dead_bytes += size;
+    }
+
+    /// Moves a previously live entry over to the dead bytes of its file
+    pub fn supersede(&mut self, fd: Fd, size: u64) {
+        let stats = self.files.entry(fd).or_default();
+        stats.live_bytes = stats.live_bytes.saturating_sub(size);
+        stats.dead_bytes += size;
+    }
+
+    /// Forgets about a file that has been deleted
+    pub fn remove(&mut self, fd: Fd) {
+        self.files.remove(&fd);
+    }
+
+    pub fn get(&self, fd: Fd) -> FileStats {
+        self.files.get(&fd).copied().unwrap_or_default()
+    }
+
+    pub fn files(&self) -> HashMap<Fd, FileStats> {
+        self.files.clone()
+    }
+}
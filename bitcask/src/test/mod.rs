@@ -1,8 +1,9 @@
 use std::{
-    cell::RefCell,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io::{self, Write},
-    sync::Arc,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 use tracing::{info, instrument, trace};
@@ -12,21 +13,42 @@ use crate::{
     ClockSource, System,
 };
 
+/// Wall-clock time of a fresh [`TestFileSystem`], in seconds since the unix epoch
+pub const TEST_EPOCH_SECS: u64 = 1_700_000_000;
+
 /// A test file system
 ///
-/// Implementors of the `FileSystem` trait do not need to be threadsafe (this might change in the
-/// future). Therefore, we can safely implement interior mutablity without using a locking scheme.
-///
 /// Interior mutability is required since we need to be able to modify the buffers backing the
-/// in-memory files in the file system.
+/// in-memory files in the file system. Even though the `Fs` layer serializes writes, reads happen
+/// concurrently under a shared lock and clones of the file system are handed out to tests, so the
+/// state lives behind a `Mutex`.
+///
+/// Time only moves forward when a test calls [`TestFileSystem::advance`], which makes timestamps
+/// and timers reproducible.
 pub struct TestFileSystem {
-    inner: Arc<RefCell<TestFsInner>>,
+    inner: Arc<Mutex<TestFsInner>>,
 }
 
 #[derive(Debug)]
 struct TestFsInner {
     buffers: HashMap<Fd, TestFile>,
+    hints: HashMap<Fd, Vec<u8>>,
     active: Fd,
+    /// Data files ordered from oldest to newest
+    files: Vec<Fd>,
+    /// Merged files which have not been committed yet
+    merging: HashSet<Fd>,
+    next_fd: Fd,
+    /// Number of times any file was synced
+    syncs: usize,
+    /// How long every sync blocks for
+    sync_delay: Duration,
+    /// Number of reads from data files
+    reads: usize,
+    /// Monotonic time at which the clock started
+    started: Instant,
+    /// Time the clock has been advanced by
+    elapsed: Duration,
 }
 
 impl Clone for TestFileSystem {
@@ -39,16 +61,54 @@ impl Clone for TestFileSystem {
 
 impl TestFileSystem {
     fn new(fd: Fd, map: HashMap<Fd, TestFile>) -> Self {
+        let mut next_fd = fd;
+        next_fd.increment();
+
         Self {
-            inner: Arc::new(RefCell::new(TestFsInner {
+            inner: Arc::new(Mutex::new(TestFsInner {
                 buffers: map,
+                hints: HashMap::new(),
                 active: fd,
+                files: vec![fd],
+                merging: HashSet::new(),
+                next_fd,
+                syncs: 0,
+                sync_delay: Duration::ZERO,
+                reads: 0,
+                started: Instant::now(),
+                elapsed: Duration::ZERO,
             })),
         }
     }
 
+    fn lock(&self) -> MutexGuard<'_, TestFsInner> {
+        self.inner.lock().expect("Unable to lock test file system")
+    }
+
     pub fn num_files(&self) -> usize {
-        self.inner.as_ref().borrow().buffers.len()
+        self.lock().buffers.len()
+    }
+
+    pub fn num_hints(&self) -> usize {
+        self.lock().hints.len()
+    }
+
+    pub fn num_syncs(&self) -> usize {
+        self.lock().syncs
+    }
+
+    /// Makes every sync block for `delay`, like a sync to a slow disk
+    pub fn set_sync_delay(&self, delay: Duration) {
+        self.lock().sync_delay = delay;
+    }
+
+    pub fn num_reads(&self) -> usize {
+        self.lock().reads
+    }
+
+    /// Moves the clock forward
+    pub fn advance(&self, by: Duration) {
+        self.lock().elapsed += by;
     }
 }
 
@@ -57,15 +117,12 @@ impl FileSystem for TestFileSystem {
     fn write_at(&self, file: Fd, buf: &[u8], offset: u64) -> std::io::Result<usize> {
         let offset = offset as usize;
         let len = buf.len();
-        let mut map = match self.inner.as_ref().try_borrow_mut() {
-            Ok(map) => map,
-            Err(err) => panic!("Unable to borrow: {:?}", err),
-        };
+        let mut map = self.lock();
         map.buffers
             .get_mut(&file)
             .map(|file_buf| {
                 info!(fd = ?file, len = buf.len(), "Writing to file");
-                file_buf.write_at(offset, &buf);
+                file_buf.write_at(offset, buf);
             })
             .ok_or(io::Error::new(
                 io::ErrorKind::NotFound,
@@ -77,10 +134,12 @@ impl FileSystem for TestFileSystem {
     fn read_exact_at(
         &self,
         file: crate::fs::Fd,
-        mut buf: &mut [u8],
+        buf: &mut [u8],
         offset: u64,
     ) -> std::io::Result<()> {
-        let buf_handle = &self.inner.as_ref().borrow().buffers;
+        let mut inner = self.lock();
+        inner.reads += 1;
+        let buf_handle = &inner.buffers;
 
         let Some(file_buf) = buf_handle.get(&file) else {
             return Err(io::Error::new(
@@ -90,7 +149,7 @@ impl FileSystem for TestFileSystem {
         };
 
         let offset = offset as usize;
-        if dbg!(file_buf.len() < offset) || dbg!((offset + buf.len()) > file_buf.len()) {
+        if file_buf.len() < offset || (offset + buf.len()) > file_buf.len() {
             return Err(io::Error::new(
                 io::ErrorKind::UnexpectedEof,
                 "reading past the end of the buffer",
@@ -98,14 +157,12 @@ impl FileSystem for TestFileSystem {
         }
 
         info!(fd = ?file, len = buf.len(), "Reading from file");
-        file_buf.read_at(offset, &mut buf)?;
+        file_buf.read_at(offset, buf)?;
         Ok(())
     }
 
     fn file_size(&self, file: crate::fs::Fd) -> std::io::Result<u64> {
-        self.inner
-            .as_ref()
-            .borrow()
+        self.lock()
             .buffers
             .get(&file)
             .map(|file_buf| {
@@ -122,8 +179,82 @@ impl FileSystem for TestFileSystem {
         Ok(())
     }
 
+    fn sync(&mut self, _file: Fd) -> std::io::Result<()> {
+        let delay = {
+            let mut inner = self.lock();
+            inner.syncs += 1;
+            inner.sync_delay
+        };
+        // Other writers keep queueing up while the sync is in progress
+        thread::sleep(delay);
+        Ok(())
+    }
+
+    fn truncate(&mut self, file: Fd, len: u64) -> std::io::Result<()> {
+        self.lock()
+            .buffers
+            .get_mut(&file)
+            .map(|file_buf| file_buf.truncate(len as usize))
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Unable to find file buf",
+            ))
+    }
+
+    fn write_hint(&mut self, file: Fd, buf: &[u8]) -> std::io::Result<()> {
+        self.lock().hints.insert(file, buf.to_vec());
+        Ok(())
+    }
+
+    fn read_hint(&self, file: Fd) -> std::io::Result<Option<Vec<u8>>> {
+        Ok(self.lock().hints.get(&file).cloned())
+    }
+
     fn active(&self) -> crate::fs::Fd {
-        self.inner.as_ref().borrow().active
+        self.lock().active
+    }
+
+    fn files(&self) -> Vec<Fd> {
+        let inner = self.lock();
+        inner
+            .files
+            .iter()
+            .filter(|fd| !inner.merging.contains(fd))
+            .copied()
+            .collect()
+    }
+
+    fn new_merge(&mut self, after: Fd) -> Result<Fd, crate::fs::FsError> {
+        let mut inner = self.lock();
+        let Some(position) = inner.files.iter().position(|fd| *fd == after) else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Unable to find file buf").into());
+        };
+
+        let fd = inner.next_fd;
+        inner.next_fd.increment();
+
+        inner.buffers.insert(fd, TestFile::new());
+        inner.merging.insert(fd);
+        inner.files.insert(position + 1, fd);
+
+        Ok(fd)
+    }
+
+    fn commit_merge(&mut self, file: Fd) -> std::io::Result<()> {
+        self.lock().merging.remove(&file);
+        Ok(())
+    }
+
+    fn remove(&mut self, file: Fd) -> std::io::Result<()> {
+        let mut inner = self.lock();
+        inner.buffers.remove(&file).ok_or(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Unable to find file buf",
+        ))?;
+        inner.hints.remove(&file);
+        inner.merging.remove(&file);
+        inner.files.retain(|fd| *fd != file);
+        Ok(())
     }
 
     fn init(_path: impl Into<std::path::PathBuf>) -> Result<Self, crate::fs::FsError>
@@ -132,36 +263,38 @@ impl FileSystem for TestFileSystem {
     {
         let fd = Fd::new_empty();
         let mut map = HashMap::new();
-        map.insert(fd.clone(), TestFile::new());
+        map.insert(fd, TestFile::new());
 
         Ok(TestFileSystem::new(fd, map))
     }
 
     #[instrument(skip(self))]
     fn new_active(&mut self) -> Result<Fd, crate::fs::FsError> {
-        self.inner.as_ref().borrow_mut().active.increment();
-        let new_active = self.inner.as_ref().borrow().active.clone();
-
-        {
-            self.inner.as_ref().borrow_mut().active = new_active;
-        }
+        let mut inner = self.lock();
+        let new_active = inner.next_fd;
+        inner.next_fd.increment();
+        inner.active = new_active;
 
+        // The previous active file stays in the map so that entries written to it remain readable
         trace!("Swapping current active file");
-        self.inner
-            .as_ref()
-            .borrow_mut()
-            .buffers
-            .insert(new_active, TestFile::new());
+        inner.buffers.insert(new_active, TestFile::new());
+        inner.files.push(new_active);
 
         Ok(new_active)
     }
 }
 
 impl System for TestFileSystem {}
-impl ClockSource for TestFileSystem {}
+impl ClockSource for TestFileSystem {
+    fn system_time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(TEST_EPOCH_SECS) + self.lock().elapsed
+    }
 
-unsafe impl Send for TestFileSystem {}
-unsafe impl Sync for TestFileSystem {}
+    fn instant(&self) -> Instant {
+        let inner = self.lock();
+        inner.started + inner.elapsed
+    }
+}
 
 #[derive(Debug)]
 struct TestFile {
@@ -184,10 +317,12 @@ impl TestFile {
             self.buf.resize(buf_end, 0);
         }
 
-        for i in 0..buf.len() {
-            self.buf[offset + i] = buf[i]
-        }
-        self.pos += buf.len();
+        self.buf[offset..buf_end].copy_from_slice(buf);
+        self.pos = self.pos.max(buf_end);
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.pos = self.pos.min(len);
     }
 
     #[instrument(skip(self, buf))]
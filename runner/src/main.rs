@@ -31,7 +31,7 @@ fn main() {
 
     let mut handles = Vec::with_capacity(opts.num_threads);
 
-    for thread in 0..opts.num_threads {
+    for _thread in 0..opts.num_threads {
         let cask = cask.clone();
         let handle = thread::spawn(move || {
             for i in 0..10000 {
@@ -42,8 +42,8 @@ fn main() {
                 // Re-create key as we move it into the cask
                 let key = format!("hello{i}");
                 let result = cask.get(&key).unwrap();
-                let val = String::from_utf8(result).unwrap();
-                // println!("thread-{thread}: {val}");
+                let _val = String::from_utf8(result).unwrap();
+                // println!("thread-{_thread}: {_val}");
             }
         });
         handles.push(handle);
@@ -0,0 +1,44 @@
+//! Fixtures shared by the integration tests
+//!
+//! Every test binary compiles its own copy of this module and most only use part of it.
+#![allow(dead_code)]
+
+use std::sync::Once;
+
+use anyhow::Result;
+use bitcask::{test::TestFileSystem, Cask, Config};
+use tracing::Level;
+
+/// Size of the file header every data file starts with
+pub const FILE_HEADER_LEN: u64 = 6;
+
+/// Size of the header in front of every entry
+pub const HEADER_LEN: u64 = 27;
+
+static TRACING: Once = Once::new();
+
+pub fn init_tracing() {
+    TRACING.call_once(|| {
+        tracing_subscriber::fmt()
+            .with_max_level(Level::TRACE)
+            .init();
+    });
+}
+
+/// Small active files, so that a few writes already spread across several data files
+pub fn config() -> Config {
+    Config {
+        active_threshold: 128,
+        ..Config::default()
+    }
+}
+
+/// Opens the data store on `test_fs` with [`config`]
+pub fn open(test_fs: &TestFileSystem) -> Result<Cask<TestFileSystem>> {
+    open_with(test_fs, config())
+}
+
+/// Opens the data store on `test_fs`
+pub fn open_with(test_fs: &TestFileSystem, config: Config) -> Result<Cask<TestFileSystem>> {
+    Ok(Cask::new_with_fs_impl("", config, test_fs.clone())?)
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use std::{
+    fs::{self, OpenOptions},
+    path::Path,
+};
+
+use anyhow::Result;
+use bitcask::{restore_backup, BackupManifest, Cask, CaskError, ConcreteSystem, Config};
+use common::config;
+
+use pretty_assertions::assert_eq;
+
+fn open(path: &Path) -> Result<Cask<ConcreteSystem>> {
+    Ok(Cask::new_with_config(path.to_str().unwrap(), config())?)
+}
+
+fn num_files(dir: &Path) -> Result<usize> {
+    Ok(fs::read_dir(dir)?.count())
+}
+
+#[test]
+fn test_incremental_backup_chain() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let backups = ["full", "first", "second"].map(|name| dir.path().join(name));
+
+    let cask = open(&dir.path().join("db"))?;
+    for i in 0..32 {
+        cask.insert(format!("key{i}"), format!("value{i}"))?;
+    }
+    let full = cask.backup_incremental(&backups[0], &BackupManifest::new())?;
+
+    // Only a handful of files have been written since the full backup
+    for i in 0..4 {
+        cask.insert(format!("key{i}"), "first")?;
+    }
+    let first = cask.backup_incremental(&backups[1], &full)?;
+    assert!(num_files(&backups[1])? < num_files(&backups[0])?);
+    assert_eq!(BackupManifest::load(&backups[1])?, first);
+
+    // Merging replaces files that are part of earlier backups
+    cask.remove(&"key5")?;
+    cask.merge()?;
+    cask.insert("key6", "second")?;
+    cask.backup_incremental(&backups[2], &first)?;
+
+    let restored = dir.path().join("restored");
+    restore_backup(&backups, &restored)?;
+    let copy = open(&restored)?;
+    assert_eq!(copy.len(), 31);
+    assert_eq!(copy.get(&"key0")?, "first".as_bytes());
+    assert!(matches!(copy.get(&"key5"), Err(CaskError::NotFound)));
+    assert_eq!(copy.get(&"key6")?, "second".as_bytes());
+    assert_eq!(copy.get(&"key31")?, "value31".as_bytes());
+
+    // Restoring from the middle of the chain gives the state at that backup
+    let restored = dir.path().join("restored-first");
+    restore_backup(&backups[..2], &restored)?;
+    let copy = open(&restored)?;
+    assert_eq!(copy.len(), 32);
+    assert_eq!(copy.get(&"key5")?, "value5".as_bytes());
+    assert_eq!(copy.get(&"key6")?, "value6".as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn test_restore_needs_whole_chain() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let (full, incremental) = (dir.path().join("full"), dir.path().join("incremental"));
+
+    let cask = open(&dir.path().join("db"))?;
+    for i in 0..32 {
+        cask.insert(format!("key{i}"), format!("value{i}"))?;
+    }
+    let manifest = cask.backup_incremental(&full, &BackupManifest::new())?;
+    cask.insert("key0", "updated")?;
+    cask.backup_incremental(&incremental, &manifest)?;
+
+    assert!(matches!(
+        restore_backup(&[&incremental], dir.path().join("restored")),
+        Err(CaskError::Fs(_))
+    ));
+
+    Ok(())
+}
+
+/// Backs up a data store whose active file then loses its last write in a crash, and gets a
+/// different write of the same size in its place. With `rotate`, the active file is sealed right
+/// after that write.
+fn backup_after_rewrite(rotate: bool) -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("db");
+    let (full, incremental) = (dir.path().join("full"), dir.path().join("incremental"));
+
+    let active = path.join("active.db");
+    let (manifest, synced_len) = {
+        let cask = open(&path)?;
+        cask.insert("first", "1")?;
+        let synced_len = fs::metadata(&active)?.len();
+        cask.insert("key", "aaaa")?;
+        (
+            cask.backup_incremental(&full, &BackupManifest::new())?,
+            synced_len,
+        )
+    };
+
+    let backed_up_len = fs::metadata(&active)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&active)?
+        .set_len(synced_len)?;
+    {
+        let config = Config {
+            active_threshold: if rotate { 1 } else { config().active_threshold },
+            ..config()
+        };
+        let cask: Cask<ConcreteSystem> = Cask::new_with_config(path.to_str().unwrap(), config)?;
+        cask.insert("key", "bbbb")?;
+        let rewritten = if rotate {
+            fs::read_dir(&path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .find(|file| file.extension().is_some_and(|ext| ext == "db") && *file != active)
+                .unwrap()
+        } else {
+            active.clone()
+        };
+        assert_eq!(fs::metadata(rewritten)?.len(), backed_up_len);
+        cask.backup_incremental(&incremental, &manifest)?;
+    }
+
+    let restored = dir.path().join("restored");
+    restore_backup(&[&full, &incremental], &restored)?;
+    let copy = open(&restored)?;
+    assert_eq!(copy.get(&"first")?, "1".as_bytes());
+    assert_eq!(copy.get(&"key")?, "bbbb".as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn test_backup_copies_changed_active_file_of_same_size() -> Result<()> {
+    backup_after_rewrite(false)
+}
+
+#[test]
+fn test_backup_copies_changed_previously_active_file_of_same_size() -> Result<()> {
+    backup_after_rewrite(true)
+}
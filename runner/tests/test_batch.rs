@@ -0,0 +1,125 @@
+mod common;
+
+use anyhow::Result;
+use bitcask::{test::TestFileSystem, Cask, CaskError, ConcreteSystem, FileSystem, WriteBatch};
+use common::{config, open};
+
+use pretty_assertions::assert_eq;
+
+#[test]
+fn test_batch_is_applied_and_replayed() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    {
+        let cask = open(&test_fs)?;
+        cask.insert("stale", "value")?;
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put("record", "1")
+            .put("index", "record")
+            .delete("stale")
+            .put("record", "2");
+        cask.write(batch)?;
+
+        assert_eq!(cask.get(&"record")?, "2".as_bytes());
+        assert_eq!(cask.get(&"index")?, "record".as_bytes());
+        assert!(matches!(cask.get(&"stale"), Err(CaskError::NotFound)));
+    }
+
+    let cask = open(&test_fs)?;
+    assert_eq!(cask.get(&"record")?, "2".as_bytes());
+    assert_eq!(cask.get(&"index")?, "record".as_bytes());
+    assert!(matches!(cask.get(&"stale"), Err(CaskError::NotFound)));
+
+    Ok(())
+}
+
+#[test]
+fn test_uncommitted_batch_is_dropped() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let active = test_fs.active();
+    let before_batch = {
+        let cask = open(&test_fs)?;
+        // Small enough for the batch to stay in the active file
+        cask.insert("a", "1")?;
+        let before_batch = test_fs.file_size(active)?;
+
+        let mut batch = WriteBatch::new();
+        batch.put("b", "2").put("c", "3");
+        cask.write(batch)?;
+        before_batch
+    };
+
+    // Cut the commit record short, as if the process died while writing it
+    let size = test_fs.file_size(active)?;
+    test_fs.clone().truncate(active, size - 2)?;
+
+    let cask = open(&test_fs)?;
+    assert_eq!(cask.get(&"a")?, "1".as_bytes());
+    assert!(matches!(cask.get(&"b"), Err(CaskError::NotFound)));
+    assert!(matches!(cask.get(&"c"), Err(CaskError::NotFound)));
+    assert_eq!(test_fs.file_size(active)?, before_batch);
+
+    Ok(())
+}
+
+#[test]
+fn test_batch_never_spans_files() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    {
+        let cask = open(&test_fs)?;
+        let mut batch = WriteBatch::new();
+        for i in 0..20 {
+            batch.put(format!("key{i}"), format!("value{i}"));
+        }
+        cask.write(batch)?;
+        cask.insert("after", "batch")?;
+    }
+
+    // The batch is larger than the threshold, yet went into a single file
+    assert_eq!(test_fs.num_files(), 2);
+
+    let cask = open(&test_fs)?;
+    for i in 0..20 {
+        assert_eq!(
+            cask.get(&format!("key{i}"))?,
+            format!("value{i}").as_bytes()
+        );
+    }
+    assert_eq!(cask.get(&"after")?, "batch".as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn test_merged_batch_survives_restart() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().to_str().unwrap();
+
+    {
+        let cask: Cask<ConcreteSystem> = Cask::new_with_config(path, config())?;
+        let mut batch = WriteBatch::new();
+        for i in 0..10 {
+            batch.put(format!("key{i}"), format!("value{i}"));
+        }
+        cask.write(batch)?;
+        for i in 0..10 {
+            cask.insert(format!("filler{i}"), "rotate the active file")?;
+        }
+
+        // Only some of the batch is still live once merged
+        cask.remove(&"key0")?;
+        cask.merge()?;
+    }
+
+    let cask: Cask<ConcreteSystem> = Cask::new_with_config(path, config())?;
+    assert!(matches!(cask.get(&"key0"), Err(CaskError::NotFound)));
+    for i in 1..10 {
+        assert_eq!(
+            cask.get(&format!("key{i}"))?,
+            format!("value{i}").as_bytes()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use anyhow::Result;
+use bitcask::{test::TestFileSystem, CacheStats, Cask, Config, FileSystem, WriteBatch};
+use common::{config, open_with};
+
+use pretty_assertions::assert_eq;
+
+fn open(test_fs: &TestFileSystem, value_cache_size: usize) -> Result<Cask<TestFileSystem>> {
+    let config = Config {
+        active_threshold: 256,
+        value_cache_size,
+        ..config()
+    };
+    open_with(test_fs, config)
+}
+
+#[test]
+fn test_cache_serves_reads() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs, 4096)?;
+
+    // Filled on insert
+    cask.insert("hello", "world")?;
+    let mut batch = WriteBatch::new();
+    batch.put("batched", "value");
+    cask.write(batch)?;
+
+    let reads = test_fs.num_reads();
+    assert_eq!(cask.get(&"hello")?, "world".as_bytes());
+    assert_eq!(cask.get(&"batched")?, "value".as_bytes());
+    assert_eq!(test_fs.num_reads(), reads);
+    assert_eq!(
+        cask.cache_stats(),
+        CacheStats {
+            hits: 2,
+            misses: 0,
+            bytes: 10,
+        }
+    );
+    drop(cask);
+
+    // Filled on read
+    let cask = open(&test_fs, 4096)?;
+    let reads = test_fs.num_reads();
+    assert_eq!(cask.get(&"hello")?, "world".as_bytes());
+    assert_eq!(test_fs.num_reads(), reads + 2);
+    assert_eq!(cask.get(&"hello")?, "world".as_bytes());
+    assert_eq!(test_fs.num_reads(), reads + 2);
+    assert_eq!(cask.cache_stats().hits, 1);
+    assert_eq!(cask.cache_stats().misses, 1);
+
+    // Overwritten values are never served
+    cask.insert("hello", "there")?;
+    assert_eq!(cask.get(&"hello")?, "there".as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn test_cache_is_bounded() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs, 100)?;
+
+    for i in 0..50 {
+        cask.insert(format!("key{i}"), format!("value{i:05}"))?;
+        assert!(cask.cache_stats().bytes <= 100);
+    }
+    for i in 0..50 {
+        assert_eq!(
+            cask.get(&format!("key{i}"))?,
+            format!("value{i:05}").as_bytes()
+        );
+    }
+    assert!(cask.cache_stats().bytes <= 100);
+    assert!(cask.cache_stats().misses > 0);
+
+    // Larger than the whole cache, never cached
+    cask.insert("large", vec![1; 200])?;
+    assert_eq!(cask.get(&"large")?, vec![1; 200]);
+    assert_eq!(cask.get(&"large")?, vec![1; 200]);
+    assert!(cask.cache_stats().bytes <= 100);
+
+    Ok(())
+}
+
+#[test]
+fn test_cache_disabled() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs, 0)?;
+
+    cask.insert("hello", "world")?;
+    assert_eq!(cask.get(&"hello")?, "world".as_bytes());
+    assert_eq!(cask.cache_stats(), CacheStats::default());
+
+    Ok(())
+}
+
+#[test]
+fn test_merge_invalidates_cache() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs, 64 * 1024)?;
+
+    for round in 0..5 {
+        for i in 0..10 {
+            cask.insert(format!("key{i}"), format!("value{i}-{round}"))?;
+        }
+    }
+    let cached = cask.cache_stats().bytes;
+    cask.merge()?;
+
+    // The values of the deleted inputs are gone, and reads go to the merged file
+    assert!(cask.cache_stats().bytes < cached);
+    let misses = cask.cache_stats().misses;
+    for i in 0..10 {
+        assert_eq!(
+            cask.get(&format!("key{i}"))?,
+            format!("value{i}-4").as_bytes()
+        );
+    }
+    assert!(cask.cache_stats().misses > misses);
+
+    Ok(())
+}
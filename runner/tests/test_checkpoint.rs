@@ -0,0 +1,64 @@
+mod common;
+
+use anyhow::Result;
+use bitcask::{test::TestFileSystem, Cask, CaskError, ConcreteSystem, FileSystem};
+use common::config;
+
+use pretty_assertions::assert_eq;
+
+#[test]
+fn test_checkpoint_is_openable_copy() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("db");
+    let dest = dir.path().join("checkpoint");
+
+    let cask: Cask<ConcreteSystem> = Cask::new_with_config(path.to_str().unwrap(), config())?;
+    for i in 0..32 {
+        cask.insert(format!("key{i}"), format!("value{i}"))?;
+    }
+    cask.remove(&"key1")?;
+    cask.merge()?;
+    cask.insert("key0", "updated")?;
+
+    cask.checkpoint(&dest)?;
+
+    // Writes after the checkpoint don't show up in it
+    cask.insert("key2", "after checkpoint")?;
+    for i in 32..64 {
+        cask.insert(format!("key{i}"), format!("value{i}"))?;
+    }
+    cask.merge()?;
+
+    let copy: Cask<ConcreteSystem> = Cask::new(dest.to_str().unwrap())?;
+    assert_eq!(copy.len(), 31);
+    assert_eq!(copy.get(&"key0")?, "updated".as_bytes());
+    assert!(matches!(copy.get(&"key1"), Err(CaskError::NotFound)));
+    assert_eq!(copy.get(&"key2")?, "value2".as_bytes());
+    assert!(matches!(copy.get(&"key32"), Err(CaskError::NotFound)));
+
+    // The copy is independent of the original
+    copy.insert("key3", "only in the copy")?;
+    assert_eq!(cask.get(&"key3")?, "value3".as_bytes());
+    assert_eq!(cask.get(&"key2")?, "after checkpoint".as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn test_checkpoint_needs_empty_directory() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let dest = dir.path().join("checkpoint");
+    std::fs::create_dir(&dest)?;
+    std::fs::write(dest.join("unrelated"), "data")?;
+
+    let cask: Cask<ConcreteSystem> = Cask::new(dir.path().join("db").to_str().unwrap())?;
+    cask.insert("hello", "world")?;
+    assert!(matches!(cask.checkpoint(&dest), Err(CaskError::Fs(_))));
+
+    // File systems without a notion of directories can't write checkpoints
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = Cask::new_with_fs_impl("", config(), test_fs)?;
+    assert!(matches!(cask.checkpoint(&dest), Err(CaskError::Fs(_))));
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use anyhow::Result;
+use bitcask::{test::TestFileSystem, Cask, Compression, Config, FileSystem, WriteBatch};
+use common::{config, open_with};
+
+use pretty_assertions::assert_eq;
+
+fn open(test_fs: &TestFileSystem, compression: Compression) -> Result<Cask<TestFileSystem>> {
+    let config = Config {
+        active_threshold: 256,
+        compression,
+        compression_threshold: 64,
+        ..config()
+    };
+    open_with(test_fs, config)
+}
+
+fn json(i: usize) -> String {
+    let fields: Vec<_> = (0..20)
+        .map(|field| format!("\"field{field}\": \"value of record {i}\""))
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+fn live_bytes(cask: &Cask<TestFileSystem>) -> u64 {
+    cask.file_stats()
+        .values()
+        .map(|stats| stats.live_bytes)
+        .sum()
+}
+
+#[test]
+fn test_compressed_values_round_trip() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs, Compression::Lz4)?;
+
+    for i in 0..10 {
+        cask.insert(format!("record{i}"), json(i))?;
+    }
+    // Below the threshold, stored as is
+    cask.insert("small", "value")?;
+    let mut batch = WriteBatch::new();
+    batch.put("batched", json(10)).put("tiny", "x");
+    cask.write(batch)?;
+
+    for i in 0..10 {
+        assert_eq!(cask.get(&format!("record{i}"))?, json(i).as_bytes());
+    }
+    assert_eq!(cask.get(&"small")?, "value".as_bytes());
+    assert_eq!(cask.get(&"batched")?, json(10).as_bytes());
+    assert_eq!(cask.get(&"tiny")?, "x".as_bytes());
+
+    let uncompressed: usize = (0..11).map(|i| json(i).len()).sum();
+    assert!(live_bytes(&cask) < uncompressed as u64 / 3);
+
+    // Compare and swap compares against the decompressed value
+    cask.compare_and_swap("record0", json(0), json(11))?;
+    assert_eq!(cask.get(&"record0")?, json(11).as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn test_mixed_codecs_decode() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs, Compression::None)?;
+    cask.insert("plain", json(0))?;
+    let plain_bytes = live_bytes(&cask);
+    drop(cask);
+
+    let cask = open(&test_fs, Compression::Lz4)?;
+    cask.insert("compressed", json(1))?;
+    assert!(live_bytes(&cask) - plain_bytes < plain_bytes / 2);
+    drop(cask);
+
+    // Switching the codec off again leaves compressed entries readable, merges included
+    let cask = open(&test_fs, Compression::None)?;
+    assert_eq!(cask.get(&"plain")?, json(0).as_bytes());
+    assert_eq!(cask.get(&"compressed")?, json(1).as_bytes());
+    cask.compact().wait()?;
+    drop(cask);
+
+    let cask = open(&test_fs, Compression::None)?;
+    assert_eq!(cask.get(&"plain")?, json(0).as_bytes());
+    assert_eq!(cask.get(&"compressed")?, json(1).as_bytes());
+
+    Ok(())
+}
@@ -0,0 +1,85 @@
+mod common;
+
+use std::thread;
+
+use anyhow::Result;
+use bitcask::{test::TestFileSystem, Cask, CaskError, FileSystem};
+use common::open;
+
+use pretty_assertions::assert_eq;
+
+const THREADS: usize = 4;
+const INCREMENTS: usize = 50;
+
+fn counter(cask: &Cask<TestFileSystem>) -> Result<usize> {
+    Ok(String::from_utf8(cask.get(&"counter")?)?.parse()?)
+}
+
+#[test]
+fn test_compare_and_swap_counter() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs)?;
+    cask.insert_if_absent("counter", "0")?;
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let cask = cask.clone();
+            thread::spawn(move || {
+                for _ in 0..INCREMENTS {
+                    loop {
+                        let current = cask.get(&"counter").unwrap();
+                        let next: usize = String::from_utf8(current.clone())
+                            .unwrap()
+                            .parse::<usize>()
+                            .unwrap()
+                            + 1;
+                        match cask.compare_and_swap("counter", &current, next.to_string()) {
+                            Ok(()) => break,
+                            // Another thread got in first, try again with its value
+                            Err(CaskError::PreconditionFailed) => continue,
+                            Err(err) => panic!("Unable to increment the counter: {err}"),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("Incrementing thread panicked");
+    }
+
+    // No increment got lost, and the log agrees with the KeyDir
+    assert_eq!(counter(&cask)?, THREADS * INCREMENTS);
+    drop(cask);
+    let cask = open(&test_fs)?;
+    assert_eq!(counter(&cask)?, THREADS * INCREMENTS);
+
+    Ok(())
+}
+
+#[test]
+fn test_conditional_writes_on_missing_keys() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs)?;
+
+    assert!(matches!(
+        cask.compare_and_swap("missing", "a", "b"),
+        Err(CaskError::PreconditionFailed)
+    ));
+    assert!(matches!(
+        cask.remove_if("missing", "a"),
+        Err(CaskError::PreconditionFailed)
+    ));
+    assert!(matches!(cask.get(&"missing"), Err(CaskError::NotFound)));
+
+    // Once removed, a key counts as absent again
+    cask.insert_if_absent("key", "1")?;
+    cask.remove_if("key", "1")?;
+    cask.insert_if_absent("key", "2")?;
+
+    drop(cask);
+    let cask = open(&test_fs)?;
+    assert_eq!(cask.get(&"key")?, "2".as_bytes());
+
+    Ok(())
+}
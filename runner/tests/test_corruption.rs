@@ -0,0 +1,41 @@
+mod common;
+
+use std::{fs::OpenOptions, os::unix::fs::FileExt};
+
+use anyhow::Result;
+use bitcask::{Cask, CaskError, ConcreteSystem};
+use common::{init_tracing, FILE_HEADER_LEN};
+
+use pretty_assertions::assert_eq;
+
+#[test]
+fn test_get_detects_bit_flip() -> Result<()> {
+    init_tracing();
+
+    let dir = tempfile::tempdir()?;
+    let cask: Cask<ConcreteSystem> = Cask::new(dir.path().to_str().unwrap())?;
+
+    cask.insert("hello", "world")?;
+    cask.insert("other", "value")?;
+    assert_eq!(cask.get(&"hello")?, "world".as_bytes());
+
+    // Flip a bit in the last byte of the first entry's value
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(dir.path().join("active.db"))?;
+    let entry_len = (file.metadata()?.len() - FILE_HEADER_LEN) / 2;
+    let mut byte = [0u8; 1];
+    file.read_exact_at(&mut byte, FILE_HEADER_LEN + entry_len - 1)?;
+    file.write_all_at(&[byte[0] ^ 1], FILE_HEADER_LEN + entry_len - 1)?;
+
+    match cask.get(&"hello") {
+        Err(CaskError::Corruption { offset, .. }) => {
+            assert_eq!(offset.0 as u64, FILE_HEADER_LEN)
+        }
+        other => panic!("Expected corruption error, got {other:?}"),
+    }
+    assert_eq!(cask.get(&"other")?, "value".as_bytes());
+
+    Ok(())
+}
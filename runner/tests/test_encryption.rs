@@ -0,0 +1,140 @@
+mod common;
+
+use anyhow::Result;
+use bitcask::{
+    test::TestFileSystem, Cask, CaskError, Config, Encryption, EncryptionError, FileSystem,
+    WriteBatch,
+};
+use common::{config, open_with};
+
+use pretty_assertions::assert_eq;
+
+const OLD_KEY: [u8; 32] = [1; 32];
+const NEW_KEY: [u8; 32] = [2; 32];
+
+fn open(test_fs: &TestFileSystem, encryption: Option<Encryption>) -> Result<Cask<TestFileSystem>> {
+    let config = Config {
+        active_threshold: 256,
+        encryption,
+        ..config()
+    };
+    open_with(test_fs, config)
+}
+
+fn open_error(test_fs: &TestFileSystem, encryption: Option<Encryption>) -> anyhow::Error {
+    open(test_fs, encryption)
+        .err()
+        .expect("Opened the data store without the right key")
+}
+
+/// Whether `needle` shows up in any data or hint file
+fn on_disk(test_fs: &TestFileSystem, needle: &[u8]) -> Result<bool> {
+    for fd in test_fs.files() {
+        let mut data = vec![0; test_fs.file_size(fd)? as usize];
+        test_fs.read_exact_at(fd, &mut data, 0)?;
+        let hints = test_fs.read_hint(fd)?.unwrap_or_default();
+
+        if [data, hints]
+            .iter()
+            .any(|file| file.windows(needle.len()).any(|window| window == needle))
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[test]
+fn test_entries_are_encrypted_on_disk() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs, Some(Encryption::new(1, OLD_KEY)))?;
+
+    for i in 0..10 {
+        cask.insert(format!("secret-key{i}"), format!("secret-value{i}"))?;
+    }
+    cask.remove(&"secret-key0")?;
+    let mut batch = WriteBatch::new();
+    batch
+        .put("secret-batched", "secret-value")
+        .delete("secret-key1");
+    cask.write(batch)?;
+
+    assert!(test_fs.num_hints() > 0);
+    assert!(!on_disk(&test_fs, b"secret")?);
+    assert_eq!(cask.get(&"secret-key2")?, "secret-value2".as_bytes());
+    drop(cask);
+
+    // Replays sealed hint files as well as the sealed active file
+    let cask = open(&test_fs, Some(Encryption::new(1, OLD_KEY)))?;
+    assert_eq!(cask.len(), 9);
+    assert_eq!(cask.get(&"secret-key9")?, "secret-value9".as_bytes());
+    assert_eq!(cask.get(&"secret-batched")?, "secret-value".as_bytes());
+    assert!(matches!(cask.get(&"secret-key1"), Err(CaskError::NotFound)));
+    drop(cask);
+
+    // The data store can't be opened without the right key
+    assert!(matches!(
+        open_error(&test_fs, None).downcast_ref::<CaskError>(),
+        Some(CaskError::Encryption(EncryptionError::NotConfigured))
+    ));
+    assert!(matches!(
+        open_error(&test_fs, Some(Encryption::new(2, NEW_KEY))).downcast_ref::<CaskError>(),
+        Some(CaskError::Encryption(EncryptionError::UnknownKey { id: 1 }))
+    ));
+    assert!(matches!(
+        open_error(&test_fs, Some(Encryption::new(1, NEW_KEY))).downcast_ref::<CaskError>(),
+        Some(CaskError::Encryption(EncryptionError::Decrypt))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_merge_rotates_keys() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+
+    // Data written before encryption was enabled, and under the old key
+    let cask = open(&test_fs, None)?;
+    for i in 0..5 {
+        cask.insert(format!("plain{i}"), format!("plain-value{i}"))?;
+    }
+    drop(cask);
+    let cask = open(&test_fs, Some(Encryption::new(1, OLD_KEY)))?;
+    for i in 0..5 {
+        cask.insert(format!("old{i}"), format!("old-value{i}"))?;
+    }
+    drop(cask);
+    assert!(on_disk(&test_fs, b"plain-value")?);
+
+    let rotated = Encryption::new(2, NEW_KEY).with_old_key(1, OLD_KEY);
+    let cask = open(&test_fs, Some(rotated))?;
+    assert_eq!(cask.get(&"plain0")?, "plain-value0".as_bytes());
+    assert_eq!(cask.get(&"old0")?, "old-value0".as_bytes());
+
+    // Seal the active file, so that the merge covers every entry written so far
+    for i in 0..5 {
+        cask.insert(format!("new{i}"), format!("new-value{i}"))?;
+    }
+    cask.merge()?;
+    drop(cask);
+
+    assert!(!on_disk(&test_fs, b"value")?);
+    let cask = open(&test_fs, Some(Encryption::new(2, NEW_KEY)))?;
+    assert_eq!(cask.len(), 15);
+    for i in 0..5 {
+        assert_eq!(
+            cask.get(&format!("plain{i}"))?,
+            format!("plain-value{i}").as_bytes()
+        );
+        assert_eq!(
+            cask.get(&format!("old{i}"))?,
+            format!("old-value{i}").as_bytes()
+        );
+        assert_eq!(
+            cask.get(&format!("new{i}"))?,
+            format!("new-value{i}").as_bytes()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,133 @@
+mod common;
+
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use bitcask::{Cask, CaskError, ConcreteSystem};
+use common::{config, init_tracing, FILE_HEADER_LEN};
+
+use pretty_assertions::assert_eq;
+
+/// Writes a few data files into `path` and returns the name of an immutable one
+fn fill(path: &Path) -> Result<String> {
+    let cask: Cask<ConcreteSystem> = Cask::new_with_config(path.to_str().unwrap(), config())?;
+    for i in 0..16 {
+        cask.insert(format!("key{i}"), format!("value{i}"))?;
+    }
+
+    let immutable = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|name| name.starts_with("immutable-") && name.ends_with(".db"))
+        .expect("No immutable file was written");
+    Ok(immutable)
+}
+
+fn open(path: &Path) -> Result<Cask<ConcreteSystem>, CaskError> {
+    Cask::new_with_config(path.to_str().unwrap(), config())
+}
+
+#[test]
+fn test_data_files_start_with_file_header() -> Result<()> {
+    init_tracing();
+
+    let dir = tempfile::tempdir()?;
+    let immutable = fill(dir.path())?;
+
+    for name in [immutable.as_str(), "active.db"] {
+        let data = fs::read(dir.path().join(name))?;
+        assert_eq!(&data[..4], b"CASK");
+        assert_eq!(&data[4..FILE_HEADER_LEN as usize], 1u16.to_le_bytes());
+    }
+
+    let cask = open(dir.path())?;
+    assert_eq!(cask.get(&"key0")?, "value0".as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn test_active_file_without_file_header_is_refused() -> Result<()> {
+    init_tracing();
+
+    let dir = tempfile::tempdir()?;
+    fill(dir.path())?;
+    open(dir.path())?.insert("extra", "value")?;
+
+    // Entries written before file headers were introduced start right at the beginning
+    let active = dir.path().join("active.db");
+    let old_format = fs::read(&active)?.split_off(FILE_HEADER_LEN as usize);
+    assert!(!old_format.is_empty());
+    fs::write(&active, &old_format)?;
+
+    assert!(matches!(
+        open(dir.path()),
+        Err(CaskError::UnsupportedFormat { .. })
+    ));
+    assert_eq!(fs::read(&active)?, old_format);
+
+    Ok(())
+}
+
+#[test]
+fn test_immutable_file_without_file_header_is_refused() -> Result<()> {
+    init_tracing();
+
+    let dir = tempfile::tempdir()?;
+    let immutable = dir.path().join(fill(dir.path())?);
+
+    let old_format = fs::read(&immutable)?.split_off(FILE_HEADER_LEN as usize);
+    fs::write(&immutable, &old_format)?;
+
+    assert!(matches!(
+        open(dir.path()),
+        Err(CaskError::UnsupportedFormat { .. })
+    ));
+    assert_eq!(fs::read(&immutable)?, old_format);
+
+    Ok(())
+}
+
+#[test]
+fn test_unknown_format_version_is_refused() -> Result<()> {
+    init_tracing();
+
+    let dir = tempfile::tempdir()?;
+    fill(dir.path())?;
+
+    let active = dir.path().join("active.db");
+    let mut data = fs::read(&active)?;
+    data[4..FILE_HEADER_LEN as usize].copy_from_slice(&2u16.to_le_bytes());
+    fs::write(&active, &data)?;
+
+    assert!(matches!(
+        open(dir.path()),
+        Err(CaskError::UnsupportedFormat { .. })
+    ));
+    assert_eq!(fs::read(&active)?, data);
+
+    Ok(())
+}
+
+#[test]
+fn test_torn_file_header_is_rewritten() -> Result<()> {
+    init_tracing();
+
+    let dir = tempfile::tempdir()?;
+    fill(dir.path())?;
+
+    // The process died right after creating the active file, part way through its file header
+    let active = dir.path().join("active.db");
+    fs::write(&active, b"CA")?;
+
+    let cask = open(dir.path())?;
+    assert_eq!(cask.get(&"key0")?, "value0".as_bytes());
+    cask.insert("after", "restart")?;
+    drop(cask);
+
+    let cask = open(dir.path())?;
+    assert_eq!(cask.get(&"after")?, "restart".as_bytes());
+
+    Ok(())
+}
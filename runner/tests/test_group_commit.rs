@@ -0,0 +1,94 @@
+mod common;
+
+use std::{
+    sync::{Arc, Barrier},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+use bitcask::{test::TestFileSystem, Cask, ConcreteSystem, Config, FileSystem};
+use common::{config, open_with};
+
+use pretty_assertions::assert_eq;
+
+const THREADS: usize = 8;
+const PER_THREAD: usize = 100;
+
+/// Inserts a distinct set of keys from every thread
+fn insert_concurrently<T: bitcask::System>(cask: &Cask<T>) {
+    // Start every writer at once, so that their writes overlap
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let cask = Cask::clone(cask);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..PER_THREAD {
+                    cask.insert(format!("key-{t}-{i}"), format!("value-{t}-{i}"))
+                        .expect("Unable to insert into the datastore");
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().expect("Writer panicked");
+    }
+}
+
+fn check<T: bitcask::System>(cask: &Cask<T>) -> Result<()> {
+    for t in 0..THREADS {
+        for i in 0..PER_THREAD {
+            assert_eq!(
+                cask.get(&format!("key-{t}-{i}"))?,
+                format!("value-{t}-{i}").as_bytes()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_concurrent_inserts_share_syncs() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    // Sealing an active file syncs it on its own, keep those out of the count
+    let config = Config {
+        active_threshold: 512,
+        ..config()
+    };
+    let cask = open_with(&test_fs, config.clone())?;
+    // Writers queue up behind a slow sync, and the next leader commits all of them at once
+    test_fs.set_sync_delay(Duration::from_millis(1));
+
+    insert_concurrently(&cask);
+    check(&cask)?;
+
+    // Every write got synced, but writes committed as a group share a single sync
+    let syncs = test_fs.num_syncs();
+    assert!(syncs < THREADS * PER_THREAD / 2, "{syncs} syncs");
+
+    drop(cask);
+    let cask = open_with(&test_fs, config)?;
+    check(&cask)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_group_commit_survives_restart() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().to_str().unwrap();
+
+    {
+        let cask: Cask<ConcreteSystem> = Cask::new_with_config(path, config())?;
+        insert_concurrently(&cask);
+        check(&cask)?;
+    }
+
+    let cask: Cask<ConcreteSystem> = Cask::new_with_config(path, config())?;
+    check(&cask)?;
+
+    Ok(())
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use bitcask::{test::TestFileSystem, Cask, Config, FileSystem, KeyDirKind};
+use common::open;
+
+use pretty_assertions::assert_eq;
+
+#[test]
+fn test_iterate_entries() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs)?;
+    assert!(cask.is_empty());
+
+    for i in 0..20 {
+        cask.insert(format!("key{i}"), format!("value{i}"))?;
+    }
+    cask.insert("key0", "updated")?;
+    cask.remove(&"key1")?;
+
+    assert_eq!(cask.len(), 19);
+    assert!(cask.contains_key(&"key0"));
+    assert!(!cask.contains_key(&"key1"));
+
+    let mut keys: Vec<_> = cask.keys().collect();
+    keys.sort();
+    let mut expected: Vec<_> = (0..20)
+        .filter(|i| *i != 1)
+        .map(|i| format!("key{i}").into_bytes())
+        .collect();
+    expected.sort();
+    assert_eq!(keys, expected);
+
+    let entries = cask.iter().collect::<Result<HashMap<_, _>, _>>()?;
+    assert_eq!(entries.len(), 19);
+    assert_eq!(entries[&b"key0".to_vec()], b"updated".to_vec());
+    assert_eq!(entries[&b"key19".to_vec()], b"value19".to_vec());
+
+    Ok(())
+}
+
+#[test]
+fn test_iterator_does_not_block_writers() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs)?;
+    for i in 0..10 {
+        cask.insert(format!("key{i}"), "before")?;
+    }
+
+    let mut seen = 0;
+    for entry in cask.iter() {
+        let (key, value) = entry?;
+        assert_eq!(value, b"before".to_vec());
+        seen += 1;
+
+        // Writing while iterating would deadlock if the iterator held on to the KeyDir lock
+        cask.insert(&key, "after")?;
+        cask.insert("new key", "not part of the iteration")?;
+        if seen == 1 {
+            for i in 0..10 {
+                if format!("key{i}").into_bytes() != key {
+                    cask.remove(&format!("key{i}"))?;
+                    break;
+                }
+            }
+        }
+    }
+
+    // One of the keys got removed before the iterator reached it
+    assert_eq!(seen, 9);
+    Ok(())
+}
+
+#[test]
+fn test_range_and_prefix_scans() -> Result<()> {
+    for kind in [KeyDirKind::Hash, KeyDirKind::Ordered, KeyDirKind::Compact] {
+        let test_fs = <TestFileSystem as FileSystem>::init("")?;
+        let config = Config {
+            active_threshold: 128,
+            keydir: kind,
+            ..Config::default()
+        };
+        let cask: Cask<TestFileSystem> =
+            Cask::new_with_fs_impl("", config.clone(), test_fs.clone())?;
+        for key in ["b/2", "a/1", "b/1", "c", "b/3", "b"] {
+            cask.insert(key, format!("{key} value"))?;
+        }
+        cask.remove(&"b/3")?;
+
+        let keys = |iter: bitcask::Iter<'_, TestFileSystem>| -> Result<Vec<String>> {
+            iter.map(|entry| Ok(String::from_utf8(entry?.0)?)).collect()
+        };
+
+        assert_eq!(keys(cask.scan_prefix("b/"))?, ["b/1", "b/2"]);
+        assert_eq!(keys(cask.range("a/1".."b/2"))?, ["a/1", "b", "b/1"]);
+        assert_eq!(keys(cask.range("b/1"..))?, ["b/1", "b/2", "c"]);
+        assert_eq!(keys(cask.range::<&str, _>(..="b"))?, ["a/1", "b"]);
+        assert!(keys(cask.range("c".."a"))?.is_empty());
+
+        let (_, value) = cask.scan_prefix("c").next().unwrap()?;
+        assert_eq!(value, b"c value".to_vec());
+
+        // The ordering survives rebuilding the KeyDir from disk
+        drop(cask);
+        let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config, test_fs)?;
+        assert_eq!(keys(cask.scan_prefix(""))?, ["a/1", "b", "b/1", "b/2", "c"]);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use anyhow::Result;
+use bitcask::{test::TestFileSystem, Cask, CaskError, Config, FileSystem, KeyDirKind};
+use common::{config, open_with};
+
+use pretty_assertions::assert_eq;
+
+fn open(test_fs: &TestFileSystem, kind: KeyDirKind) -> Result<Cask<TestFileSystem>> {
+    let config = Config {
+        active_threshold: 4096,
+        keydir: kind,
+        ..config()
+    };
+    open_with(test_fs, config)
+}
+
+#[test]
+fn test_compact_keydir() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs, KeyDirKind::Compact)?;
+
+    for i in 0..2000 {
+        cask.insert(format!("user/{i}"), format!("{i}"))?;
+    }
+    for i in (0..2000).step_by(3) {
+        cask.remove(&format!("user/{i}"))?;
+    }
+    for i in (0..2000).step_by(5) {
+        cask.insert(format!("user/{i}"), format!("{i} again"))?;
+    }
+    cask.merge()?;
+
+    let check = |cask: &Cask<TestFileSystem>| -> Result<()> {
+        for i in 0..2000 {
+            let key = format!("user/{i}");
+            match (i % 5, i % 3) {
+                (0, _) => assert_eq!(cask.get(&key)?, format!("{i} again").as_bytes()),
+                (_, 0) => assert!(matches!(cask.get(&key), Err(CaskError::NotFound))),
+                _ => assert_eq!(cask.get(&key)?, format!("{i}").as_bytes()),
+            }
+        }
+        Ok(())
+    };
+    check(&cask)?;
+    let expected = cask.len();
+    assert_eq!(cask.keydir_memory().keys, expected);
+
+    drop(cask);
+    let cask = open(&test_fs, KeyDirKind::Compact)?;
+    check(&cask)?;
+    assert_eq!(cask.len(), expected);
+
+    Ok(())
+}
+
+#[test]
+fn test_keydir_memory() -> Result<()> {
+    let mut bytes_per_key = Vec::new();
+    for kind in [KeyDirKind::Hash, KeyDirKind::Ordered, KeyDirKind::Compact] {
+        let test_fs = <TestFileSystem as FileSystem>::init("")?;
+        let cask = open(&test_fs, kind)?;
+        assert_eq!(cask.keydir_memory().bytes_per_key(), 0.0);
+
+        for i in 0..10_000 {
+            cask.insert(format!("key{i:05}"), "value")?;
+        }
+        let memory = cask.keydir_memory();
+        assert_eq!(memory.keys, 10_000);
+        // Nothing packs the 8 byte keys and their locations into less
+        assert!(memory.bytes_per_key() > 8.0 + 16.0);
+        bytes_per_key.push(memory.bytes_per_key());
+    }
+
+    let [hash, ordered, compact] = bytes_per_key[..] else {
+        unreachable!()
+    };
+    assert!(compact < hash / 2.0, "{compact} vs {hash}");
+    assert!(compact < ordered / 2.0, "{compact} vs {ordered}");
+
+    Ok(())
+}
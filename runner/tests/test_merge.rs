@@ -0,0 +1,289 @@
+mod common;
+
+use std::{
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use bitcask::{test::TestFileSystem, Cask, CaskError, ConcreteSystem, Config, FileSystem};
+use common::{config, init_tracing};
+
+use pretty_assertions::assert_eq;
+
+/// Overwrites every key a few times and deletes some of them
+fn churn<T: bitcask::System>(cask: &Cask<T>) -> Result<()> {
+    for round in 0..5 {
+        for i in 0..10 {
+            cask.insert(format!("key{i}"), format!("value{i}-{round}"))?;
+        }
+    }
+    for i in 0..3 {
+        cask.remove(&format!("key{i}"))?;
+    }
+    Ok(())
+}
+
+fn check<T: bitcask::System>(cask: &Cask<T>) -> Result<()> {
+    for i in 0..3 {
+        assert!(matches!(
+            cask.get(&format!("key{i}")),
+            Err(CaskError::NotFound)
+        ));
+    }
+    for i in 3..10 {
+        assert_eq!(
+            cask.get(&format!("key{i}"))?,
+            format!("value{i}-4").as_bytes()
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_merge_reclaims_space() -> Result<()> {
+    init_tracing();
+
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+    churn(&cask)?;
+
+    let files_before = test_fs.num_files();
+    cask.merge()?;
+
+    // Every immutable file got folded into a single merged file
+    assert!(files_before > 2);
+    assert_eq!(test_fs.num_files(), 2);
+    assert_eq!(test_fs.num_hints(), 1);
+    check(&cask)?;
+
+    // Writes after the merge still win over the merged entries
+    cask.insert("key3", "after merge")?;
+    drop(cask);
+
+    let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+    assert_eq!(cask.get(&"key3")?, "after merge".as_bytes());
+    assert_eq!(cask.get(&"key9")?, "value9-4".as_bytes());
+
+    Ok(())
+}
+
+fn data_files(path: &Path) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.ends_with(".db") {
+            files.push(name);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[test]
+fn test_merge_survives_restart() -> Result<()> {
+    init_tracing();
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().to_str().unwrap();
+
+    {
+        let cask: Cask<ConcreteSystem> = Cask::new_with_config(path, config())?;
+        churn(&cask)?;
+        cask.merge()?;
+        check(&cask)?;
+
+        // Merge the merged file together with files written since
+        churn(&cask)?;
+        cask.merge()?;
+        check(&cask)?;
+    }
+
+    let files = data_files(dir.path())?;
+    assert_eq!(files.len(), 2);
+    assert!(files.contains(&"active.db".to_string()));
+
+    let cask: Cask<ConcreteSystem> = Cask::new_with_config(path, config())?;
+    check(&cask)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_file_stats_track_dead_bytes() -> Result<()> {
+    init_tracing();
+
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+    churn(&cask)?;
+
+    // Seven keys survive, each entry is a 27 byte header, a 4 byte key and an 8 byte value
+    let stats = cask.file_stats();
+    let live: u64 = stats.values().map(|file| file.live_bytes).sum();
+    assert_eq!(live, 7 * 39);
+    assert!(stats.values().any(|file| file.dead_ratio() > 0.5));
+
+    // Replaying the data files arrives at the same numbers
+    drop(cask);
+    let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+    assert_eq!(cask.file_stats(), stats);
+
+    // After a merge only the live entries and the active file remain
+    cask.merge()?;
+    let live_after: u64 = cask.file_stats().values().map(|file| file.live_bytes).sum();
+    let dead_after: u64 = cask.file_stats().values().map(|file| file.dead_bytes).sum();
+    assert_eq!(live_after, live);
+    assert!(dead_after < stats.values().map(|file| file.dead_bytes).sum());
+
+    Ok(())
+}
+
+#[test]
+fn test_fragmentation_triggers_merge() -> Result<()> {
+    init_tracing();
+
+    let config = Config {
+        merge_dead_ratio: 0.5,
+        merge_check_interval: Duration::from_secs(60),
+        ..config()
+    };
+
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config, test_fs.clone())?.init();
+    churn(&cask)?;
+
+    // The clock only moves when told to, so the check interval never elapses on its own
+    let files_before = test_fs.num_files();
+    assert!(files_before > 2);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(test_fs.num_files(), files_before);
+
+    test_fs.advance(Duration::from_secs(60));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while test_fs.num_files() > 2 {
+        assert!(Instant::now() < deadline, "Background merge never ran");
+        thread::sleep(Duration::from_millis(10));
+    }
+    check(&cask)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_single_fragmented_file_triggers_merge() -> Result<()> {
+    init_tracing();
+
+    let config = Config {
+        merge_dead_ratio: 0.5,
+        merge_check_interval: Duration::from_secs(60),
+        ..config()
+    };
+
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config, test_fs.clone())?.init();
+    for i in 0..40 {
+        cask.insert(format!("key{i}"), format!("value{i}"))?;
+    }
+    // Leaves the oldest file mostly dead, while most bytes across all files are still live
+    for i in 0..3 {
+        cask.insert(format!("key{i}"), "updated")?;
+    }
+    let stats = cask.file_stats();
+    assert!(stats.values().any(|file| file.dead_ratio() > 0.5));
+    let (live, dead) = stats.values().fold((0, 0), |(live, dead), file| {
+        (live + file.live_bytes, dead + file.dead_bytes)
+    });
+    assert!(dead < live);
+
+    // Give the compaction loop time to start before moving the clock past the check interval
+    let files_before = test_fs.num_files();
+    thread::sleep(Duration::from_millis(300));
+    test_fs.advance(Duration::from_secs(60));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while test_fs.num_files() >= files_before {
+        assert!(Instant::now() < deadline, "Background merge never ran");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(cask.get(&"key0")?, "updated".as_bytes());
+    assert_eq!(cask.get(&"key39")?, "value39".as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn test_compact_reports_progress() -> Result<()> {
+    init_tracing();
+
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+    churn(&cask)?;
+
+    let immutable = test_fs.num_files() - 1;
+    let handle = cask.compact();
+    let progress = handle.wait()?;
+
+    assert_eq!(progress.files_processed, immutable);
+    assert_eq!(progress.entries_copied, 7);
+    assert!(progress.bytes_reclaimed > 0);
+    assert_eq!(test_fs.num_files(), 2);
+    check(&cask)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_compact_cancelled() -> Result<()> {
+    init_tracing();
+
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+    for i in 0..2000 {
+        cask.insert(
+            format!("key{}", i % 10),
+            format!("value{}-{}", i % 10, i / 10),
+        )?;
+    }
+
+    let files_before = test_fs.num_files();
+    let handle = cask.compact();
+    handle.cancel();
+
+    assert!(matches!(handle.wait(), Err(CaskError::Cancelled)));
+    // The merged file got cleaned up and the inputs were left alone
+    assert_eq!(test_fs.num_files(), files_before);
+    for i in 0..10 {
+        assert_eq!(
+            cask.get(&format!("key{i}"))?,
+            format!("value{i}-199").as_bytes()
+        );
+    }
+
+    // Compacting again afterwards works as usual
+    cask.compact().wait()?;
+    assert_eq!(test_fs.num_files(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_compactions_never_overlap() -> Result<()> {
+    init_tracing();
+
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+    churn(&cask)?;
+
+    let handles: Vec<_> = (0..3).map(|_| cask.compact()).collect();
+    let copied: Vec<_> = handles
+        .into_iter()
+        .map(|handle| handle.wait().map(|progress| progress.entries_copied))
+        .collect::<Result<_, _>>()?;
+
+    // Each merge only ever sees the complete output of the one before it
+    assert_eq!(copied, vec![7, 7, 7]);
+    assert_eq!(test_fs.num_files(), 2);
+    check(&cask)?;
+
+    Ok(())
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use anyhow::Result;
+use bitcask::{Cask, Compression, ConcreteSystem, Config, MmapSystem, ValueRef};
+use common::config;
+
+use pretty_assertions::assert_eq;
+
+fn fill<T: bitcask::System>(cask: &Cask<T>) -> Result<()> {
+    for round in 0..3 {
+        for i in 0..20 {
+            cask.insert(format!("key{i}"), format!("value{i}-{round}"))?;
+        }
+    }
+    Ok(())
+}
+
+fn check<T: bitcask::System>(cask: &Cask<T>) -> Result<()> {
+    for i in 0..20 {
+        let expected = format!("value{i}-2");
+        assert_eq!(cask.get(&format!("key{i}"))?, expected.as_bytes());
+        assert_eq!(&*cask.get_ref(&format!("key{i}"))?, expected.as_bytes());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_mmap_reads() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().to_str().unwrap();
+
+    let cask: Cask<MmapSystem> = Cask::new_with_config(path, config())?;
+    fill(&cask)?;
+    check(&cask)?;
+
+    // Rotated files are mapped, values borrowed from them
+    assert!(matches!(cask.get_ref(&"key0")?, ValueRef::Mapped(_)));
+    cask.insert("active", "value")?;
+    assert!(matches!(cask.get_ref(&"active")?, ValueRef::Owned(_)));
+
+    // Borrowed values outlive the file they were mapped from
+    let borrowed = cask.get_ref(&"key0")?;
+    cask.merge()?;
+    assert_eq!(&*borrowed, "value0-2".as_bytes());
+    check(&cask)?;
+    drop(cask);
+
+    let cask: Cask<MmapSystem> = Cask::new_with_config(path, config())?;
+    check(&cask)?;
+    assert!(matches!(cask.get_ref(&"key0")?, ValueRef::Mapped(_)));
+
+    Ok(())
+}
+
+#[test]
+fn test_mmap_opens_concrete_files() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().to_str().unwrap();
+
+    let cask: Cask<ConcreteSystem> = Cask::new_with_config(path, config())?;
+    fill(&cask)?;
+    // Without maps, every value is read into memory
+    assert!(matches!(cask.get_ref(&"key0")?, ValueRef::Owned(_)));
+    drop(cask);
+
+    let cask: Cask<MmapSystem> = Cask::new_with_config(path, config())?;
+    check(&cask)?;
+    fill(&cask)?;
+    drop(cask);
+
+    let cask: Cask<ConcreteSystem> = Cask::new_with_config(path, config())?;
+    check(&cask)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_get_ref_decodes_compressed_values() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = Config {
+        compression: Compression::Lz4,
+        compression_threshold: 0,
+        ..config()
+    };
+    let cask: Cask<MmapSystem> = Cask::new_with_config(dir.path().to_str().unwrap(), config)?;
+
+    let value = "compressible ".repeat(20);
+    cask.insert("compressed", &value)?;
+    cask.insert("rotate", vec![0; 256])?;
+
+    let compressed = cask.get_ref(&"compressed")?;
+    assert!(matches!(compressed, ValueRef::Owned(_)));
+    assert_eq!(&*compressed, value.as_bytes());
+
+    Ok(())
+}
@@ -0,0 +1,221 @@
+mod common;
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use bitcask::{
+    test::TestFileSystem, Cask, CaskError, ClockSource, ConcreteSystem, Config, FileSystem,
+    SyncPolicy, WriteBatch,
+};
+use common::config;
+
+use pretty_assertions::assert_eq;
+
+#[test]
+fn test_open_as_of_ignores_later_writes() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+
+    for i in 0..10 {
+        cask.insert(format!("key{i}"), "good")?;
+    }
+    cask.remove(&"key9")?;
+    cask.insert_with_ttl("session", "token", Duration::from_secs(90))?;
+    test_fs.advance(Duration::from_secs(30));
+    let before_deploy = test_fs.system_time();
+
+    // A bad deploy overwrites and removes keys, spread across several data files
+    test_fs.advance(Duration::from_secs(30));
+    for i in 0..5 {
+        cask.insert(format!("key{i}"), "bad")?;
+    }
+    cask.remove(&"key5")?;
+    cask.insert("key9", "bad")?;
+    let mut batch = WriteBatch::new();
+    batch.put("key6", "bad").delete("key7").put("new", "bad");
+    cask.write(batch)?;
+    drop(cask);
+
+    let as_of = Cask::open_as_of_with_fs_impl(config(), test_fs.clone(), before_deploy)?;
+    assert_eq!(as_of.len(), 10);
+    for i in 0..9 {
+        assert_eq!(as_of.get(&format!("key{i}"))?, "good".as_bytes());
+    }
+    assert!(matches!(as_of.get(&"key9"), Err(CaskError::NotFound)));
+    assert!(!as_of.contains_key(&"new"));
+    assert_eq!(as_of.get(&"session")?, "token".as_bytes());
+
+    // The source is left untouched
+    drop(as_of);
+    let cask = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+    assert_eq!(cask.get(&"key0")?, "bad".as_bytes());
+    assert!(matches!(cask.get(&"key7"), Err(CaskError::NotFound)));
+
+    Ok(())
+}
+
+#[test]
+fn test_export_as_of() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+
+    cask.insert("config", "good")?;
+    cask.insert_with_ttl("session", "token", Duration::from_secs(60))?;
+    cask.insert_with_ttl("expired", "token", Duration::from_secs(10))?;
+    test_fs.advance(Duration::from_secs(20));
+    let before_deploy = test_fs.system_time();
+
+    test_fs.advance(Duration::from_secs(10));
+    cask.insert("config", "bad")?;
+    cask.remove(&"session")?;
+    drop(cask);
+
+    let as_of = Cask::open_as_of_with_fs_impl(config(), test_fs.clone(), before_deploy)?;
+    let target_fs = <TestFileSystem as FileSystem>::init("")?;
+    let restored = Cask::new_with_fs_impl("", config(), target_fs.clone())?;
+    as_of.export(&restored)?;
+
+    assert_eq!(restored.len(), 2);
+    assert_eq!(restored.get(&"config")?, "good".as_bytes());
+    assert_eq!(restored.get(&"session")?, "token".as_bytes());
+    assert!(matches!(restored.get(&"expired"), Err(CaskError::NotFound)));
+
+    // The session keeps the 40 seconds it had left at the time of the snapshot
+    target_fs.advance(Duration::from_secs(39));
+    assert_eq!(restored.get(&"session")?, "token".as_bytes());
+    target_fs.advance(Duration::from_secs(1));
+    assert!(matches!(restored.get(&"session"), Err(CaskError::NotFound)));
+
+    Ok(())
+}
+
+fn read_dir(path: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut files = BTreeMap::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        files.insert(
+            entry.file_name().to_string_lossy().into_owned(),
+            fs::read(entry.path())?,
+        );
+    }
+    Ok(files)
+}
+
+#[test]
+fn test_open_as_of_leaves_files_untouched() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("db");
+    {
+        let cask: Cask<ConcreteSystem> = Cask::new_with_config(path.to_str().unwrap(), config())?;
+        for i in 0..10 {
+            cask.insert(format!("key{i}"), format!("value{i}"))?;
+        }
+        cask.insert("last", "value")?;
+    }
+
+    // Leftovers of an interrupted merge, a missing hint file and a torn write at the end of the
+    // active file would all be cleaned up by opening the data store for writing
+    let files = read_dir(&path)?;
+    let immutable = files
+        .keys()
+        .find(|name| name.ends_with(".db") && *name != "active.db")
+        .unwrap();
+    fs::write(path.join(format!("{immutable}.merge")), b"partial merge")?;
+    fs::write(path.join(format!("{immutable}.tmp")), b"partial checkpoint")?;
+    let hint = files.keys().find(|name| name.ends_with(".hint")).unwrap();
+    fs::remove_file(path.join(hint))?;
+    OpenOptions::new()
+        .append(true)
+        .open(path.join("active.db"))?
+        .write_all(&[0xff; 8])?;
+    let before = read_dir(&path)?;
+
+    let as_of = Cask::<ConcreteSystem>::open_as_of(
+        path.to_str().unwrap(),
+        config(),
+        SystemTime::now() + Duration::from_secs(1),
+    )?;
+    assert_eq!(as_of.len(), 11);
+    assert_eq!(as_of.get(&"key0")?, "value0".as_bytes());
+    assert_eq!(as_of.get(&"last")?, "value".as_bytes());
+    drop(as_of);
+
+    assert_eq!(read_dir(&path)?, before);
+
+    Ok(())
+}
+
+#[test]
+fn test_open_as_of_without_active_file() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("db");
+    let dest = dir.path().join("checkpoint");
+    {
+        let cask: Cask<ConcreteSystem> = Cask::new_with_config(path.to_str().unwrap(), config())?;
+        for i in 0..10 {
+            cask.insert(format!("key{i}"), format!("value{i}"))?;
+        }
+        cask.checkpoint(&dest)?;
+    }
+    // Checkpoints seal the active file, there is none to open
+    assert!(!dest.join("active.db").exists());
+    let before = read_dir(&dest)?;
+
+    let as_of = Cask::<ConcreteSystem>::open_as_of(
+        dest.to_str().unwrap(),
+        config(),
+        SystemTime::now() + Duration::from_secs(1),
+    )?;
+    assert_eq!(as_of.len(), 10);
+    assert_eq!(as_of.get(&"key9")?, "value9".as_bytes());
+    drop(as_of);
+
+    assert!(!dest.join("active.db").exists());
+    assert_eq!(read_dir(&dest)?, before);
+
+    Ok(())
+}
+
+/// Number of live threads of the data store's thread pool, in any data store of this process
+fn pool_threads() -> Result<usize> {
+    let mut threads = 0;
+    for task in fs::read_dir("/proc/self/task")? {
+        let comm = fs::read_to_string(task?.path().join("comm"))?;
+        if comm.starts_with("bitcask-pool") {
+            threads += 1;
+        }
+    }
+    Ok(threads)
+}
+
+#[test]
+fn test_open_as_of_starts_no_threads() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    {
+        let cask = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+        cask.insert("hello", "world")?;
+    }
+
+    // A sync timer would be pointless, the view never writes
+    let config = Config {
+        sync: SyncPolicy::Interval(Duration::from_secs(1)),
+        ..config()
+    };
+    let as_of = Cask::open_as_of_with_fs_impl(config, test_fs.clone(), test_fs.system_time())?;
+    assert_eq!(as_of.get(&"hello")?, "world".as_bytes());
+    // Threads only take on their name once they are running
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(pool_threads()?, 0);
+
+    drop(as_of);
+    assert_eq!(pool_threads()?, 0);
+
+    Ok(())
+}
@@ -0,0 +1,296 @@
+mod common;
+
+use std::{fs::OpenOptions, os::unix::fs::FileExt};
+
+use anyhow::Result;
+use bitcask::{test::TestFileSystem, Cask, CaskError, ConcreteSystem, Fd, FileSystem};
+use common::{config, init_tracing, FILE_HEADER_LEN, HEADER_LEN};
+
+use pretty_assertions::assert_eq;
+
+#[test]
+fn test_rebuild_keydir_from_all_files() -> Result<()> {
+    init_tracing();
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().to_str().unwrap();
+
+    {
+        let cask: Cask<ConcreteSystem> = Cask::new_with_config(path, config())?;
+        for i in 0..32 {
+            cask.insert(format!("key{i}"), format!("value{i}"))?;
+        }
+
+        // Overwrite and delete keys whose original values now live in immutable files
+        cask.insert("key0", "updated")?;
+        cask.remove(&"key1")?;
+    }
+
+    let cask: Cask<ConcreteSystem> = Cask::new_with_config(path, config())?;
+
+    assert_eq!(cask.get(&"key0")?, "updated".as_bytes());
+    assert!(matches!(cask.get(&"key1"), Err(CaskError::NotFound)));
+    for i in 2..32 {
+        assert_eq!(
+            cask.get(&format!("key{i}"))?,
+            format!("value{i}").as_bytes()
+        );
+    }
+
+    // New writes after a restart must land after the existing entries
+    cask.insert("key2", "after restart")?;
+    drop(cask);
+
+    let cask: Cask<ConcreteSystem> = Cask::new_with_config(path, config())?;
+    assert_eq!(cask.get(&"key2")?, "after restart".as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn test_rebuild_keydir_test_fs() -> Result<()> {
+    init_tracing();
+
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    {
+        let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+        for i in 0..32 {
+            cask.insert(format!("key{i}"), format!("value{i}"))?;
+        }
+        cask.remove(&"key3")?;
+    }
+
+    assert!(test_fs.num_files() > 1);
+
+    let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+    assert!(matches!(cask.get(&"key3"), Err(CaskError::NotFound)));
+    assert_eq!(cask.get(&"key31")?, "value31".as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn test_truncate_torn_tail() -> Result<()> {
+    init_tracing();
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().to_str().unwrap();
+
+    {
+        let cask: Cask<ConcreteSystem> = Cask::new(path)?;
+        cask.insert("hello", "world")?;
+    }
+
+    // Simulate a crash half way through writing a second entry
+    let active = dir.path().join("active.db");
+    let valid_len = std::fs::metadata(&active)?.len();
+    let file = OpenOptions::new().append(true).open(&active)?;
+    file.write_all_at(&[0xAB; 7], valid_len)?;
+
+    let cask: Cask<ConcreteSystem> = Cask::new(path)?;
+    assert_eq!(cask.get(&"hello")?, "world".as_bytes());
+    assert_eq!(std::fs::metadata(&active)?.len(), valid_len);
+
+    // The next write takes the place of the garbage
+    cask.insert("second", "entry")?;
+    drop(cask);
+
+    let cask: Cask<ConcreteSystem> = Cask::new(path)?;
+    assert_eq!(cask.get(&"hello")?, "world".as_bytes());
+    assert_eq!(cask.get(&"second")?, "entry".as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn test_truncate_checksum_failing_tail() -> Result<()> {
+    init_tracing();
+
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    {
+        let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+        cask.insert("first", "1")?;
+        cask.insert("second", "2")?;
+    }
+
+    // Clobber the value of the last entry, as if only its header had made it to disk
+    let active = test_fs.active();
+    let size = test_fs.file_size(active)?;
+    test_fs.write_at(active, b"x", size - 1)?;
+
+    let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+    assert_eq!(cask.get(&"first")?, "1".as_bytes());
+    assert!(matches!(cask.get(&"second"), Err(CaskError::NotFound)));
+
+    Ok(())
+}
+
+#[test]
+fn test_truncate_entry_torn_after_its_header() -> Result<()> {
+    init_tracing();
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().to_str().unwrap();
+
+    let active = dir.path().join("active.db");
+    let valid_len = {
+        let cask: Cask<ConcreteSystem> = Cask::new(path)?;
+        cask.insert("hello", "world")?;
+        let valid_len = std::fs::metadata(&active)?.len();
+        cask.insert("second", "entry")?;
+        valid_len
+    };
+
+    // The header of the second entry made it to disk, but not all of its value
+    let torn_len = std::fs::metadata(&active)?.len() - 3;
+    OpenOptions::new()
+        .write(true)
+        .open(&active)?
+        .set_len(torn_len)?;
+
+    let cask: Cask<ConcreteSystem> = Cask::new(path)?;
+    assert_eq!(cask.get(&"hello")?, "world".as_bytes());
+    assert!(matches!(cask.get(&"second"), Err(CaskError::NotFound)));
+    assert_eq!(std::fs::metadata(&active)?.len(), valid_len);
+
+    Ok(())
+}
+
+#[test]
+fn test_truncate_zero_filled_tail() -> Result<()> {
+    init_tracing();
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().to_str().unwrap();
+
+    {
+        let cask: Cask<ConcreteSystem> = Cask::new(path)?;
+        for i in 0..3 {
+            cask.insert(format!("key{i}"), format!("value{i}"))?;
+        }
+    }
+
+    // A crash can leave the file extended with zeros instead of the data of the last write. A
+    // header of zeros describes a short entry which fails its checksum.
+    let active = dir.path().join("active.db");
+    let valid_len = std::fs::metadata(&active)?.len();
+    let file = OpenOptions::new().append(true).open(&active)?;
+    file.write_all_at(&[0; 100], valid_len)?;
+
+    let cask: Cask<ConcreteSystem> = Cask::new(path)?;
+    for i in 0..3 {
+        assert_eq!(
+            cask.get(&format!("key{i}"))?,
+            format!("value{i}").as_bytes()
+        );
+    }
+    assert_eq!(std::fs::metadata(&active)?.len(), valid_len);
+
+    Ok(())
+}
+
+#[test]
+fn test_damaged_header_is_not_truncated() -> Result<()> {
+    init_tracing();
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().to_str().unwrap();
+
+    {
+        let cask: Cask<ConcreteSystem> = Cask::new(path)?;
+        for i in 0..3 {
+            cask.insert(format!("key{i}"), format!("value{i}"))?;
+        }
+    }
+
+    // Flip the top bit of the value size of the first entry, which makes it run past the end of
+    // the file. The entries behind it are intact, so this is no torn write.
+    let active = dir.path().join("active.db");
+    let value_size_end = FILE_HEADER_LEN + HEADER_LEN;
+    let file = OpenOptions::new().read(true).write(true).open(&active)?;
+    let mut byte = [0u8; 1];
+    file.read_exact_at(&mut byte, value_size_end - 1)?;
+    file.write_all_at(&[byte[0] ^ 0x80], value_size_end - 1)?;
+    let damaged = std::fs::read(&active)?;
+
+    match Cask::<ConcreteSystem>::new(path) {
+        Err(CaskError::Corruption { offset, .. }) => {
+            assert_eq!(offset.0 as u64, FILE_HEADER_LEN)
+        }
+        Err(err) => panic!("Expected corruption error, got {err:?}"),
+        Ok(_) => panic!("Opened a data store with a damaged header"),
+    }
+    assert_eq!(std::fs::read(&active)?, damaged);
+
+    Ok(())
+}
+
+#[test]
+fn test_rebuild_keydir_from_hint_files() -> Result<()> {
+    init_tracing();
+
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    {
+        let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+        for i in 0..32 {
+            cask.insert(format!("key{i}"), format!("value{i}"))?;
+        }
+    }
+
+    // Every immutable file gets a hint file
+    assert_eq!(test_fs.num_hints(), test_fs.num_files() - 1);
+
+    // Damage the value of the very first entry. Replaying the data file would trip over the
+    // checksum, but the hint file allows skipping the values altogether.
+    // The last byte of the first entry, behind the file header: Header + key0 + value0
+    let last_byte = FILE_HEADER_LEN + HEADER_LEN + "key0".len() as u64 + "value0".len() as u64 - 1;
+    test_fs.write_at(Fd::new_empty(), b"x", last_byte)?;
+
+    let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config(), test_fs.clone())?;
+    assert_eq!(cask.get(&"key31")?, "value31".as_bytes());
+    assert!(matches!(
+        cask.get(&"key0"),
+        Err(CaskError::Corruption { .. })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_missing_hint_files_are_recreated() -> Result<()> {
+    init_tracing();
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().to_str().unwrap();
+
+    {
+        let cask: Cask<ConcreteSystem> = Cask::new_with_config(path, config())?;
+        for i in 0..32 {
+            cask.insert(format!("key{i}"), format!("value{i}"))?;
+        }
+    }
+
+    let hints = || -> Result<Vec<_>> {
+        let mut hints = std::fs::read_dir(dir.path())?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .filter(|name| {
+                name.as_ref()
+                    .map_or(true, |name| name.to_string_lossy().ends_with(".hint"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        hints.sort();
+        Ok(hints)
+    };
+
+    let written = hints()?;
+    assert!(!written.is_empty());
+    for hint in &written {
+        std::fs::remove_file(dir.path().join(hint))?;
+    }
+
+    let cask: Cask<ConcreteSystem> = Cask::new_with_config(path, config())?;
+    assert_eq!(cask.get(&"key0")?, "value0".as_bytes());
+    assert_eq!(hints()?, written);
+
+    Ok(())
+}
@@ -0,0 +1,162 @@
+mod common;
+
+use std::{collections::HashMap, thread, time::Duration};
+
+use anyhow::Result;
+use bitcask::{test::TestFileSystem, CaskError, Config, FileSystem};
+use common::{config, open, open_with};
+
+use pretty_assertions::assert_eq;
+
+#[test]
+fn test_snapshot_ignores_later_writes() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs)?;
+    for i in 0..10 {
+        cask.insert(format!("key{i}"), format!("value{i}"))?;
+    }
+    cask.insert_with_ttl("session", "token", Duration::from_secs(10))?;
+
+    let snapshot = cask.snapshot();
+    cask.insert("key0", "updated")?;
+    cask.remove(&"key1")?;
+    cask.insert("new", "value")?;
+    test_fs.advance(Duration::from_secs(10));
+
+    assert_eq!(snapshot.get(&"key0")?, "value0".as_bytes());
+    assert_eq!(snapshot.get(&"key1")?, "value1".as_bytes());
+    assert!(matches!(snapshot.get(&"new"), Err(CaskError::NotFound)));
+    // Time stands still for a snapshot
+    assert_eq!(snapshot.get(&"session")?, "token".as_bytes());
+    assert!(matches!(cask.get(&"session"), Err(CaskError::NotFound)));
+
+    assert_eq!(snapshot.len(), 11);
+    let entries = snapshot.iter().collect::<Result<HashMap<_, _>, _>>()?;
+    assert_eq!(entries.len(), 11);
+    assert_eq!(entries[&b"key9".to_vec()], b"value9".to_vec());
+
+    let keys = snapshot
+        .scan_prefix("key")
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(keys.len(), 10);
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+
+    Ok(())
+}
+
+#[test]
+fn test_merge_keeps_files_of_open_snapshots() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs)?;
+    for round in 0..3 {
+        for i in 0..10 {
+            cask.insert(format!("key{i}"), format!("value{i}-{round}"))?;
+        }
+    }
+    cask.remove(&"key0")?;
+
+    let snapshot = cask.snapshot();
+    let files_before = test_fs.num_files();
+    cask.merge()?;
+
+    // The merged file has been added, but the files it replaced are still around
+    assert_eq!(test_fs.num_files(), files_before + 1);
+    assert_eq!(snapshot.get(&"key5")?, "value5-2".as_bytes());
+    assert!(matches!(snapshot.get(&"key0"), Err(CaskError::NotFound)));
+    assert_eq!(cask.get(&"key5")?, "value5-2".as_bytes());
+
+    // Replaying the retired files along with the merged one, as after a crash, is harmless
+    {
+        let reopened = open(&test_fs)?;
+        assert!(matches!(reopened.get(&"key0"), Err(CaskError::NotFound)));
+        for i in 1..10 {
+            assert_eq!(
+                reopened.get(&format!("key{i}"))?,
+                format!("value{i}-2").as_bytes()
+            );
+        }
+    }
+
+    // Dropping the last snapshot deletes the retired files
+    let other = cask.snapshot();
+    drop(snapshot);
+    assert_eq!(test_fs.num_files(), files_before + 1);
+    drop(other);
+    assert!(test_fs.num_files() < files_before);
+    assert_eq!(cask.get(&"key5")?, "value5-2".as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn test_later_merges_leave_retired_files_alone() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs)?;
+    for round in 0..3 {
+        for i in 0..10 {
+            cask.insert(format!("key{i}"), format!("value{i}-{round}"))?;
+        }
+    }
+
+    let snapshot = cask.snapshot();
+    cask.merge()?;
+    let files_after_merge = test_fs.num_files();
+
+    // The tombstone lands in a new file, while older values of the key sit in retired files
+    cask.remove(&"key1")?;
+    for i in 2..10 {
+        cask.insert(format!("key{i}"), format!("value{i}-3"))?;
+    }
+    let files_before = test_fs.num_files();
+    let retired = files_after_merge - 2;
+    let progress = cask.compact().wait()?;
+
+    // Only the merged file and the files written since got merged again, all of which the
+    // snapshot might still read
+    assert_eq!(progress.files_processed, files_before - 1 - retired);
+    assert_eq!(test_fs.num_files(), files_before + 1);
+    assert!(cask.file_stats().len() <= 2);
+    assert_eq!(snapshot.get(&"key1")?, "value1-2".as_bytes());
+
+    // The merged file kept the tombstone, which still shadows the retired files
+    {
+        let reopened = open(&test_fs)?;
+        assert!(matches!(reopened.get(&"key1"), Err(CaskError::NotFound)));
+        assert_eq!(reopened.get(&"key2")?, "value2-3".as_bytes());
+    }
+
+    drop(snapshot);
+    assert_eq!(test_fs.num_files(), 2);
+    assert!(matches!(cask.get(&"key1"), Err(CaskError::NotFound)));
+
+    Ok(())
+}
+
+#[test]
+fn test_retired_files_do_not_trigger_merges() -> Result<()> {
+    let config = Config {
+        merge_check_interval: Duration::from_secs(60),
+        ..config()
+    };
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open_with(&test_fs, config)?.init();
+    for round in 0..3 {
+        for i in 0..10 {
+            cask.insert(format!("key{i}"), format!("value{i}-{round}"))?;
+        }
+    }
+
+    let snapshot = cask.snapshot();
+    cask.merge()?;
+    let files_after_merge = test_fs.num_files();
+
+    // Give the compaction loop time to start before moving the clock past the check interval
+    thread::sleep(Duration::from_millis(300));
+    test_fs.advance(Duration::from_secs(60));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(test_fs.num_files(), files_after_merge);
+    assert_eq!(snapshot.get(&"key5")?, "value5-2".as_bytes());
+
+    Ok(())
+}
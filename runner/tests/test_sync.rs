@@ -0,0 +1,130 @@
+mod common;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use bitcask::{test::TestFileSystem, Cask, Config, FileSystem, SyncPolicy};
+use common::open_with;
+
+use pretty_assertions::assert_eq;
+
+fn open(sync: SyncPolicy) -> Result<(Cask<TestFileSystem>, TestFileSystem)> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let config = Config {
+        sync,
+        ..Config::default()
+    };
+    let cask = open_with(&test_fs, config)?;
+    Ok((cask, test_fs))
+}
+
+fn insert_n(cask: &Cask<TestFileSystem>, n: usize) -> Result<()> {
+    for i in 0..n {
+        cask.insert(format!("key{i}"), "value")?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sync_always() -> Result<()> {
+    let (cask, test_fs) = open(SyncPolicy::Always)?;
+    insert_n(&cask, 10)?;
+    cask.remove(&"key0")?;
+    assert_eq!(test_fs.num_syncs(), 11);
+    Ok(())
+}
+
+#[test]
+fn test_sync_every_n() -> Result<()> {
+    let (cask, test_fs) = open(SyncPolicy::EveryN(3))?;
+    insert_n(&cask, 10)?;
+    assert_eq!(test_fs.num_syncs(), 3);
+    Ok(())
+}
+
+#[test]
+fn test_sync_interval() -> Result<()> {
+    let (cask, test_fs) = open(SyncPolicy::Interval(Duration::from_secs(1)))?;
+    insert_n(&cask, 5)?;
+    assert_eq!(test_fs.num_syncs(), 0);
+
+    test_fs.advance(Duration::from_secs(1));
+    insert_n(&cask, 5)?;
+    assert_eq!(test_fs.num_syncs(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_sync_interval_without_further_writes() -> Result<()> {
+    let (cask, test_fs) = open(SyncPolicy::Interval(Duration::from_secs(1)))?;
+    insert_n(&cask, 5)?;
+    assert_eq!(test_fs.num_syncs(), 0);
+
+    // The timer syncs the writes, even though nothing else gets written
+    test_fs.advance(Duration::from_secs(1));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while test_fs.num_syncs() == 0 {
+        assert!(Instant::now() < deadline, "Interval sync never ran");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(test_fs.num_syncs(), 1);
+
+    // Nothing left to sync
+    test_fs.advance(Duration::from_secs(1));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(test_fs.num_syncs(), 1);
+    drop(cask);
+    assert_eq!(test_fs.num_syncs(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_sync_on_drop() -> Result<()> {
+    let (cask, test_fs) = open(SyncPolicy::EveryN(100))?;
+    insert_n(&cask, 5)?;
+    let other = cask.clone();
+    drop(cask);
+    assert_eq!(test_fs.num_syncs(), 0);
+
+    // Dropping the last handle syncs what the sync policy left unsynced
+    drop(other);
+    assert_eq!(test_fs.num_syncs(), 1);
+
+    // Unless the sync policy never syncs
+    let (cask, test_fs) = open(SyncPolicy::Never)?;
+    insert_n(&cask, 5)?;
+    drop(cask);
+    assert_eq!(test_fs.num_syncs(), 0);
+    Ok(())
+}
+
+#[test]
+fn test_sync_never() -> Result<()> {
+    let (cask, test_fs) = open(SyncPolicy::Never)?;
+    insert_n(&cask, 10)?;
+    assert_eq!(test_fs.num_syncs(), 0);
+
+    // Callers can still force a sync
+    cask.sync()?;
+    assert_eq!(test_fs.num_syncs(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_sync_on_rotation() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let config = Config {
+        active_threshold: 64,
+        sync: SyncPolicy::EveryN(100),
+        ..Config::default()
+    };
+    let cask: Cask<TestFileSystem> = Cask::new_with_fs_impl("", config, test_fs.clone())?;
+
+    // Each entry is 36 bytes, so the second one seals the active file
+    insert_n(&cask, 2)?;
+    assert_eq!(test_fs.num_syncs(), 1);
+    Ok(())
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use std::time::Duration;
+
+use anyhow::Result;
+use bitcask::{test::TestFileSystem, CaskError, FileSystem};
+use common::open;
+
+use pretty_assertions::assert_eq;
+
+#[test]
+fn test_expired_entries_are_not_found() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs)?;
+
+    cask.insert_with_ttl("session", "token", Duration::from_secs(10))?;
+    cask.insert_with_ttl("renewed", "token", Duration::from_secs(10))?;
+    cask.insert("forever", "value")?;
+
+    test_fs.advance(Duration::from_secs(9));
+    assert_eq!(cask.get(&"session")?, "token".as_bytes());
+    // Overwriting a key replaces its ttl along with its value
+    cask.insert("renewed", "no ttl")?;
+
+    test_fs.advance(Duration::from_secs(1));
+    assert!(matches!(cask.get(&"session"), Err(CaskError::NotFound)));
+    assert!(!cask.contains_key(&"session"));
+    assert_eq!(cask.len(), 2);
+    let mut keys: Vec<_> = cask.keys().collect();
+    keys.sort();
+    assert_eq!(keys, vec![b"forever".to_vec(), b"renewed".to_vec()]);
+
+    // An expired key counts as absent for conditional writes
+    cask.insert_if_absent("session", "new token")?;
+    assert_eq!(cask.get(&"session")?, "new token".as_bytes());
+
+    // Expired entries stay expired after a restart
+    cask.insert_with_ttl("short", "lived", Duration::from_millis(500))?;
+    drop(cask);
+    test_fs.advance(Duration::from_secs(1));
+    let cask = open(&test_fs)?;
+    assert!(matches!(cask.get(&"short"), Err(CaskError::NotFound)));
+    assert_eq!(cask.get(&"renewed")?, "no ttl".as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn test_merge_drops_expired_entries() -> Result<()> {
+    let test_fs = <TestFileSystem as FileSystem>::init("")?;
+    let cask = open(&test_fs)?;
+
+    for i in 0..10 {
+        cask.insert_with_ttl(format!("key{i}"), "value", Duration::from_secs(60))?;
+    }
+    cask.insert("kept", "value")?;
+    // Seal everything written so far, so that it is all part of the merge
+    for i in 0..4 {
+        cask.insert(format!("filler{i}"), "value")?;
+    }
+
+    test_fs.advance(Duration::from_secs(60));
+    let progress = cask.compact().wait()?;
+
+    // Only "kept" and "filler0" made it into the merged file, the active file holds the rest of
+    // the fillers
+    assert_eq!(progress.entries_copied, 2);
+    // Each expiring entry is a 27 byte header, a 4 byte key and a 5 byte value
+    assert!(progress.bytes_reclaimed >= 10 * 36);
+    assert!(matches!(cask.get(&"key0"), Err(CaskError::NotFound)));
+    assert_eq!(cask.get(&"kept")?, "value".as_bytes());
+    assert_eq!(cask.len(), 5);
+
+    Ok(())
+}